@@ -0,0 +1,755 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use mirajazz::types::{ImageMirroring, ImageMode, ImageRotation};
+use serde::Deserialize;
+
+use crate::mappings::{KEY_COUNT, KEY_MAP, QuerySpec, parse_usage};
+
+/// Environment variable with brightness applied on connect, 0 - 100, takes precedence over the config file
+pub const BRIGHTNESS_ENV: &str = "OPENDECK_AMPGD6_BRIGHTNESS";
+
+/// Environment variable with [Config::usage], takes precedence over the config file
+pub const USAGE_OVERRIDE_ENV: &str = "OPENDECK_AMPGD6_USAGE";
+
+/// Environment variable with [Config::extra_queries] as a comma-separated list, takes precedence
+/// over the config file
+pub const EXTRA_QUERIES_ENV: &str = "OPENDECK_AMPGD6_EXTRA_QUERIES";
+
+/// Environment variable with [Config::skip_builtin_queries], `1` or `true` to set it, takes
+/// precedence over the config file
+pub const SKIP_BUILTIN_QUERIES_ENV: &str = "OPENDECK_AMPGD6_SKIP_BUILTIN_QUERIES";
+
+/// Environment variable with [Config::device_names] as semicolon-separated `hid_path=name`
+/// pairs, e.g. `/dev/hidraw3=Deck Left;/dev/hidraw4=Deck Right`, takes precedence over the
+/// config file
+pub const DEVICE_NAMES_ENV: &str = "OPENDECK_AMPGD6_DEVICE_NAMES";
+
+/// What happens to an image OpenDeck sends for a button while an animation plays on it,
+/// clearing the button always stops the animation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationUpdates {
+    /// Image stops the animation and takes its place right away
+    Replace,
+    /// Image takes the place of the animation once it played to its last frame, only the
+    /// latest image is kept
+    Queue,
+    /// Image is dropped, the animation keeps playing
+    Ignore,
+}
+
+/// Plugin configuration, every field is optional in the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Brightness applied on connect, 0 - 100
+    #[serde(skip)]
+    pub brightness: u8,
+    /// Whether repeated identical events are dropped, turning it off lets fast double presses through
+    pub dedup_enabled: bool,
+    /// Window in which repeated identical button events are dropped
+    pub dedup_window_ms: u64,
+    /// Window in which repeated identical encoder presses and releases are dropped,
+    /// twists are never dropped as duplicates
+    pub encoder_dedup_window_ms: u64,
+    /// Time after a release in which another press of the same button is treated as
+    /// contact bounce and dropped, 0 disables it
+    pub bounce_window_ms: u64,
+    /// Time without input after which the device is sent a heartbeat to check the link, 0 disables it
+    pub watchdog_interval_ms: u64,
+    /// Time allowed for connecting to and initializing the device before the attempt is abandoned
+    pub init_timeout_ms: u64,
+    /// Time without input after which the device is dimmed to reduce burn-in, 0 disables it
+    pub idle_timeout_ms: u64,
+    /// Brightness applied while the device is idle, 0 - 100, 0 blanks the screens
+    #[serde(skip)]
+    pub idle_brightness: u8,
+    /// OpenDeck position of a button that dims the device and brings it back on demand, its
+    /// presses aren't sent to OpenDeck. None by default
+    pub screensaver_key: Option<u8>,
+    /// Window in which image updates are collected and flushed together, 0 flushes every update
+    pub flush_debounce_ms: u64,
+    /// Factor every encoder step is multiplied by, e.g. 0.5 sends a step to OpenDeck for every
+    /// second one, see [Config::encoder_delta]
+    pub encoder_sensitivity: f32,
+    /// Whether encoder direction is reversed
+    pub encoder_invert: bool,
+    /// Whether a button whose image can't be decoded shows a red cross instead of its previous image
+    pub broken_image_placeholder: bool,
+    /// Font size of text labels in pixels, see [crate::device::set_button_text]
+    #[cfg(feature = "text")]
+    pub text_font_size: f32,
+    /// Color of text labels as RGB
+    #[cfg(feature = "text")]
+    pub text_color: [u8; 3],
+    /// Quality of JPEG images sent to the device, 1 - 100
+    #[serde(skip)]
+    pub jpeg_quality: u8,
+    /// What happens to images OpenDeck sends for a button playing an animation: `replace`,
+    /// `queue` or `ignore`, see [AnimationUpdates], `replace` if not set
+    pub animation_updates: Option<String>,
+    /// Number of animations whose encoded frames are kept for showing them again, 0 keeps none
+    pub animation_cache_size: usize,
+    /// Encoding of button images: `jpeg` or `bmp`, the device default if not set
+    pub image_mode: Option<String>,
+    /// Rotation of button images in degrees: 0, 90, 180 or 270, the device default if not set
+    pub rotation: Option<u16>,
+    /// Mirroring of button images: `none`, `x`, `y` or `both`, the device default if not set
+    pub mirror: Option<String>,
+    /// Protocol version used to talk to every device, 1 - 3, the device default if not set
+    pub force_protocol_version: Option<usize>,
+    /// OpenDeck to device key index mapping, `key_map[opendeck_index] = device_index`
+    pub key_map: Vec<u8>,
+    /// Usage page and usage id to probe the built-in devices on instead of their own, as a
+    /// `usage_page:usage_id` pair in hex, e.g. `ff00:1`, for OS and driver combinations that
+    /// report the device on another interface
+    pub usage: Option<String>,
+    /// Extra queries to probe, as `usage_page:usage_id:vid:pid` tuples in hex, e.g. `ffa0:1:3142:0009`
+    pub extra_queries: Vec<String>,
+    /// Whether only the extra queries are probed and the built-in ones are skipped, ignored
+    /// when there are no extra queries
+    pub skip_builtin_queries: bool,
+    /// Custom names to register devices with, keyed by HID path, see [crate::mappings::hid_path]
+    /// for when the path of a device can change
+    pub device_names: HashMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            brightness: 50,
+            dedup_enabled: true,
+            dedup_window_ms: 500,
+            encoder_dedup_window_ms: 500,
+            bounce_window_ms: 30,
+            watchdog_interval_ms: 10_000,
+            init_timeout_ms: 10_000,
+            idle_timeout_ms: 0,
+            idle_brightness: 10,
+            screensaver_key: None,
+            flush_debounce_ms: 0,
+            encoder_sensitivity: 1.0,
+            encoder_invert: false,
+            broken_image_placeholder: false,
+            #[cfg(feature = "text")]
+            text_font_size: 20.0,
+            #[cfg(feature = "text")]
+            text_color: [255, 255, 255],
+            jpeg_quality: 85,
+            animation_updates: None,
+            animation_cache_size: 16,
+            image_mode: None,
+            rotation: None,
+            mirror: None,
+            force_protocol_version: None,
+            key_map: KEY_MAP.to_vec(),
+            usage: None,
+            extra_queries: vec![],
+            skip_builtin_queries: false,
+            device_names: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns path of the config file, `~/.config/opendeck-ampgd6/config.toml`
+    pub fn path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME")
+                    .or_else(|| std::env::var_os("USERPROFILE"))
+                    .map(|home| PathBuf::from(home).join(".config"))
+            })?;
+
+        Some(config_dir.join("opendeck-ampgd6").join("config.toml"))
+    }
+
+    /// Loads config from the config file, falling back to defaults if it's missing or malformed
+    pub fn load() -> Self {
+        let mut config = match Self::path() {
+            Some(path) => match std::fs::read_to_string(&path) {
+                Ok(contents) => match Self::from_toml(&contents) {
+                    Ok(config) => {
+                        log::info!("Loaded config from {}", path.display());
+
+                        config
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "Failed to parse config {}, using defaults: {}",
+                            path.display(),
+                            err
+                        );
+
+                        Self::default()
+                    }
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+                Err(err) => {
+                    log::error!(
+                        "Failed to read config {}, using defaults: {}",
+                        path.display(),
+                        err
+                    );
+
+                    Self::default()
+                }
+            },
+            None => Self::default(),
+        };
+
+        if let Some(brightness) = brightness_from_env() {
+            config.brightness = brightness;
+        }
+
+        if let Ok(usage) = std::env::var(USAGE_OVERRIDE_ENV) {
+            config.usage = Some(usage);
+        }
+
+        if let Ok(queries) = std::env::var(EXTRA_QUERIES_ENV) {
+            config.extra_queries = queries
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(|entry| entry.trim().to_string())
+                .collect();
+        }
+
+        if let Ok(skip) = std::env::var(SKIP_BUILTIN_QUERIES_ENV) {
+            config.skip_builtin_queries = skip == "1" || skip.eq_ignore_ascii_case("true");
+        }
+
+        if let Some(names) = device_names_from_env() {
+            config.device_names = names;
+        }
+
+        config.validate();
+
+        config
+    }
+
+    /// Parses config file contents, clamping out of range values instead of failing
+    pub fn from_toml(contents: &str) -> Result<Self, toml::de::Error> {
+        let raw: RawConfig = toml::from_str(contents)?;
+        let mut config = raw.config;
+
+        if let Some(brightness) = raw.brightness {
+            config.brightness = clamped("Brightness", brightness, 0, 100);
+        }
+
+        if let Some(idle_brightness) = raw.idle_brightness {
+            config.idle_brightness = clamped("Idle brightness", idle_brightness, 0, 100);
+        }
+
+        if let Some(jpeg_quality) = raw.jpeg_quality {
+            config.jpeg_quality = clamped("JPEG quality", jpeg_quality, 1, 100);
+        }
+
+        Ok(config)
+    }
+
+    /// Returns what happens to images OpenDeck sends for a button playing an animation
+    pub fn animation_updates(&self) -> AnimationUpdates {
+        match self.animation_updates.as_deref() {
+            Some("queue") => AnimationUpdates::Queue,
+            Some("ignore") => AnimationUpdates::Ignore,
+            _ => AnimationUpdates::Replace,
+        }
+    }
+
+    /// Returns encoding override for button images, if set
+    pub fn image_mode(&self) -> Option<ImageMode> {
+        match self.image_mode.as_deref()? {
+            "jpeg" => Some(ImageMode::JPEG),
+            "bmp" => Some(ImageMode::BMP),
+            _ => None,
+        }
+    }
+
+    /// Returns rotation override for button images, if set
+    pub fn image_rotation(&self) -> Option<ImageRotation> {
+        match self.rotation? {
+            0 => Some(ImageRotation::Rot0),
+            90 => Some(ImageRotation::Rot90),
+            180 => Some(ImageRotation::Rot180),
+            270 => Some(ImageRotation::Rot270),
+            _ => None,
+        }
+    }
+
+    /// Returns mirroring override for button images, if set
+    pub fn image_mirroring(&self) -> Option<ImageMirroring> {
+        match self.mirror.as_deref()? {
+            "none" => Some(ImageMirroring::None),
+            "x" => Some(ImageMirroring::X),
+            "y" => Some(ImageMirroring::Y),
+            "both" => Some(ImageMirroring::Both),
+            _ => None,
+        }
+    }
+
+    /// Returns usage page and usage id to probe the built-in devices on, if set
+    pub fn usage_override(&self) -> Option<(u16, u16)> {
+        parse_usage(self.usage.as_deref()?)
+    }
+
+    /// Returns extra queries to probe next to the built-in ones
+    pub fn extra_queries(&self) -> Vec<QuerySpec> {
+        self.extra_queries
+            .iter()
+            .filter_map(|entry| QuerySpec::parse(entry))
+            .collect()
+    }
+
+    /// Returns whole encoder steps to send to OpenDeck for steps reported by the device, scaled
+    /// by sensitivity, then inverted if configured
+    ///
+    /// The fraction of a step left over is kept in `remainder` and added to the next twist of
+    /// the same encoder, so a sensitivity of 0.5 sends a step for every second one reported
+    pub fn encoder_delta(&self, raw: i8, remainder: &mut f32) -> i16 {
+        let scaled = raw as f32 * self.encoder_sensitivity + *remainder;
+
+        // Fractions like 0.1 don't add up to exactly one step in floating point
+        let steps = if (scaled - scaled.round()).abs() < 1e-4 {
+            scaled.round()
+        } else {
+            scaled.trunc()
+        };
+        *remainder = scaled - steps;
+
+        let delta = steps as i16;
+
+        if self.encoder_invert { -delta } else { delta }
+    }
+
+    /// Replaces out of range values, logging each of them
+    pub fn validate(&mut self) {
+        let defaults = Self::default();
+
+        self.brightness = clamped("Brightness", self.brightness.into(), 0, 100);
+        self.idle_brightness = clamped("Idle brightness", self.idle_brightness.into(), 0, 100);
+
+        if let Some(key) = self.screensaver_key
+            && key as usize >= KEY_COUNT
+        {
+            log::warn!(
+                "Screensaver key must be 0 - {}, got {}, not binding it",
+                KEY_COUNT - 1,
+                key
+            );
+
+            self.screensaver_key = None;
+        }
+
+        if self.init_timeout_ms == 0 {
+            log::warn!("Init timeout can't be 0, using default");
+
+            self.init_timeout_ms = defaults.init_timeout_ms;
+        }
+
+        if !(self.encoder_sensitivity.is_finite() && self.encoder_sensitivity > 0.0) {
+            log::warn!(
+                "Encoder sensitivity must be above 0, got {}, using default",
+                self.encoder_sensitivity
+            );
+
+            self.encoder_sensitivity = defaults.encoder_sensitivity;
+        }
+
+        #[cfg(feature = "text")]
+        if !(self.text_font_size.is_finite() && self.text_font_size > 0.0) {
+            log::warn!(
+                "Text font size must be above 0, got {}, using default",
+                self.text_font_size
+            );
+
+            self.text_font_size = defaults.text_font_size;
+        }
+
+        self.jpeg_quality = clamped("JPEG quality", self.jpeg_quality.into(), 1, 100);
+
+        if let Some(updates) = &self.animation_updates
+            && !matches!(updates.as_str(), "replace" | "queue" | "ignore")
+        {
+            log::warn!(
+                "Animation updates must be replace, queue or ignore, got {:?}, using replace",
+                updates
+            );
+
+            self.animation_updates = None;
+        }
+
+        if let Some(mode) = &self.image_mode
+            && self.image_mode().is_none()
+        {
+            log::warn!(
+                "Image mode must be jpeg or bmp, got {:?}, using device default",
+                mode
+            );
+
+            self.image_mode = None;
+        }
+
+        if let Some(rotation) = self.rotation
+            && !matches!(rotation, 0 | 90 | 180 | 270)
+        {
+            log::warn!(
+                "Rotation must be 0, 90, 180 or 270, got {}, using device default",
+                rotation
+            );
+
+            self.rotation = None;
+        }
+
+        if let Some(mirror) = &self.mirror
+            && self.image_mirroring().is_none()
+        {
+            log::warn!(
+                "Mirror must be none, x, y or both, got {:?}, using device default",
+                mirror
+            );
+
+            self.mirror = None;
+        }
+
+        if let Some(version) = self.force_protocol_version
+            && !(1..=3).contains(&version)
+        {
+            log::warn!(
+                "Protocol version must be 1, 2 or 3, got {}, using device default",
+                version
+            );
+
+            self.force_protocol_version = None;
+        }
+
+        if self.key_map.len() != KEY_COUNT {
+            log::error!(
+                "Key map must have exactly {} entries, got {}, using default",
+                KEY_COUNT,
+                self.key_map.len()
+            );
+
+            self.key_map = defaults.key_map;
+        } else if let Some(invalid) = key_map_errors(&self.key_map) {
+            log::error!(
+                "Key map must use every index 0 - {} exactly once, {}, using default",
+                KEY_COUNT - 1,
+                invalid
+            );
+
+            self.key_map = defaults.key_map;
+        }
+
+        if let Some(usage) = &self.usage
+            && self.usage_override().is_none()
+        {
+            log::warn!(
+                "Usage must be usage_page:usage_id in hex, got {:?}, using the device one",
+                usage
+            );
+
+            self.usage = None;
+        }
+
+        self.extra_queries.retain(|entry| {
+            let valid = QuerySpec::parse(entry).is_some();
+
+            if !valid {
+                log::warn!(
+                    "Ignoring invalid extra query {:?}, expected usage_page:usage_id:vid:pid in hex",
+                    entry
+                );
+            }
+
+            valid
+        });
+
+        self.device_names.retain(|path, name| {
+            let valid = !path.trim().is_empty() && !name.trim().is_empty();
+
+            if !valid {
+                log::warn!("Ignoring device name {:?} for path {:?}", name, path);
+            }
+
+            valid
+        });
+    }
+}
+
+/// Describes entries that keep key map from being a permutation of 0..[KEY_COUNT],
+/// the ones out of range and the ones used more than once, none if it's valid
+fn key_map_errors(key_map: &[u8]) -> Option<String> {
+    let out_of_range: Vec<u8> = key_map
+        .iter()
+        .copied()
+        .filter(|&index| index as usize >= KEY_COUNT)
+        .collect();
+
+    let mut seen = [false; KEY_COUNT];
+    let mut duplicates: Vec<u8> = vec![];
+    for &index in key_map {
+        if let Some(seen) = seen.get_mut(index as usize)
+            && std::mem::replace(seen, true)
+            && !duplicates.contains(&index)
+        {
+            duplicates.push(index);
+        }
+    }
+
+    match (out_of_range.is_empty(), duplicates.is_empty()) {
+        (true, true) => None,
+        (false, true) => Some(format!("out of range: {:?}", out_of_range)),
+        (true, false) => Some(format!("duplicated: {:?}", duplicates)),
+        (false, false) => Some(format!(
+            "out of range: {:?}, duplicated: {:?}",
+            out_of_range, duplicates
+        )),
+    }
+}
+
+/// Reads device names from [DEVICE_NAMES_ENV], invalid entries are logged and skipped
+fn device_names_from_env() -> Option<HashMap<String, String>> {
+    let value = std::env::var(DEVICE_NAMES_ENV).ok()?;
+
+    Some(parse_device_names(&value))
+}
+
+/// Parses semicolon-separated `hid_path=name` pairs, invalid entries are logged and skipped
+fn parse_device_names(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| match entry.split_once('=') {
+            Some((path, name)) if !path.trim().is_empty() && !name.trim().is_empty() => {
+                Some((path.trim().to_string(), name.trim().to_string()))
+            }
+            _ => {
+                log::warn!(
+                    "Ignoring invalid entry in {}: {:?}, expected hid_path=name",
+                    DEVICE_NAMES_ENV,
+                    entry
+                );
+
+                None
+            }
+        })
+        .collect()
+}
+
+/// Config file contents, the ranged values are read as wider integers so an out of range
+/// value is clamped instead of failing the whole file
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(flatten)]
+    config: Config,
+    brightness: Option<i64>,
+    idle_brightness: Option<i64>,
+    jpeg_quality: Option<i64>,
+}
+
+/// Returns the value clamped to min - max, logging it if it's out of range
+fn clamped(name: &str, value: i64, min: u8, max: u8) -> u8 {
+    if !(min.into()..=max.into()).contains(&value) {
+        log::warn!(
+            "{} is out of range ({} - {}): {}, clamping",
+            name,
+            min,
+            max,
+            value
+        );
+    }
+
+    value.clamp(min.into(), max.into()) as u8
+}
+
+/// Reads brightness from [BRIGHTNESS_ENV], clamping it to 0 - 100
+fn brightness_from_env() -> Option<u8> {
+    let value = std::env::var(BRIGHTNESS_ENV).ok()?;
+
+    match value.trim().parse::<i64>() {
+        Ok(brightness) => Some(clamped(BRIGHTNESS_ENV, brightness, 0, 100)),
+        Err(_) => {
+            log::warn!("{} is not a number: {:?}, ignoring", BRIGHTNESS_ENV, value);
+
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns steps sent for every twist, keeping the remainder between them
+    fn deltas(config: &Config, twists: &[i8]) -> Vec<i16> {
+        let mut remainder = 0.0;
+
+        twists
+            .iter()
+            .map(|&raw| config.encoder_delta(raw, &mut remainder))
+            .collect()
+    }
+
+    fn with_sensitivity(encoder_sensitivity: f32) -> Config {
+        Config {
+            encoder_sensitivity,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn half_sensitivity_sends_every_second_step() {
+        assert_eq!(deltas(&with_sensitivity(0.5), &[1, 1, 1, 1]), [0, 1, 0, 1]);
+        assert_eq!(deltas(&with_sensitivity(0.5), &[-1, -1]), [0, -1]);
+    }
+
+    #[test]
+    fn low_sensitivities_add_up_to_steps() {
+        assert_eq!(
+            deltas(&with_sensitivity(0.25), &[1; 8]).iter().sum::<i16>(),
+            2
+        );
+        assert_eq!(
+            deltas(&with_sensitivity(0.1), &[1; 10]).iter().sum::<i16>(),
+            1
+        );
+    }
+
+    #[test]
+    fn high_sensitivity_multiplies_steps() {
+        assert_eq!(deltas(&with_sensitivity(2.0), &[1, -1]), [2, -2]);
+        assert_eq!(deltas(&with_sensitivity(1.5), &[1, 1]), [1, 2]);
+    }
+
+    #[test]
+    fn inverted_encoder_reverses_steps() {
+        let config = Config {
+            encoder_invert: true,
+            ..with_sensitivity(0.5)
+        };
+
+        assert_eq!(deltas(&config, &[1, 1, -1, -1]), [0, -1, 0, 1]);
+        assert_eq!(deltas(&config, &[4, -4]), [-2, 2]);
+    }
+
+    #[test]
+    fn invalid_queries_are_dropped() {
+        let mut config = Config {
+            usage: Some("ff00".to_string()),
+            extra_queries: vec![
+                "ffa0:1:3142:0009".to_string(),
+                "ffa0:1:3142".to_string(),
+                "0xffa0:0x1:0x3142:0x000a".to_string(),
+                "ffa0:1:3142:zz".to_string(),
+            ],
+            ..Config::default()
+        };
+        config.validate();
+
+        assert_eq!(config.usage, None);
+        assert_eq!(
+            config.extra_queries(),
+            [
+                QuerySpec {
+                    usage_page: 0xffa0,
+                    usage_id: 1,
+                    vid: 0x3142,
+                    pid: 0x0009,
+                },
+                QuerySpec {
+                    usage_page: 0xffa0,
+                    usage_id: 1,
+                    vid: 0x3142,
+                    pid: 0x000a,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn usage_is_parsed() {
+        let mut config = Config {
+            usage: Some("ff00:1".to_string()),
+            ..Config::default()
+        };
+        config.validate();
+
+        assert_eq!(config.usage_override(), Some((0xff00, 1)));
+    }
+
+    #[test]
+    fn device_names_are_parsed() {
+        let names =
+            parse_device_names("/dev/hidraw3=Deck Left; /dev/hidraw4 = Deck Right;broken;=x");
+
+        assert_eq!(
+            names,
+            HashMap::from([
+                ("/dev/hidraw3".to_string(), "Deck Left".to_string()),
+                ("/dev/hidraw4".to_string(), "Deck Right".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_device_names_are_dropped() {
+        let mut config = Config {
+            device_names: HashMap::from([
+                ("/dev/hidraw3".to_string(), "Deck".to_string()),
+                ("/dev/hidraw4".to_string(), " ".to_string()),
+            ]),
+            ..Config::default()
+        };
+        config.validate();
+
+        assert_eq!(config.device_names.len(), 1);
+    }
+
+    #[test]
+    fn out_of_range_values_keep_the_rest_of_the_file() {
+        let mut config = Config::from_toml(
+            "brightness = 300\nidle_brightness = -5\njpeg_quality = 1000\ndedup_window_ms = 20",
+        )
+        .unwrap();
+        config.validate();
+
+        assert_eq!(config.brightness, 100);
+        assert_eq!(config.idle_brightness, 0);
+        assert_eq!(config.jpeg_quality, 100);
+        assert_eq!(config.dedup_window_ms, 20);
+    }
+
+    #[test]
+    fn invalid_key_maps_fall_back_to_default() {
+        let mut duplicate: Vec<u8> = (0..KEY_COUNT as u8).collect();
+        duplicate[1] = 0;
+        let mut out_of_range: Vec<u8> = (0..KEY_COUNT as u8).collect();
+        out_of_range[14] = 15;
+
+        assert_eq!(key_map_errors(&duplicate).unwrap(), "duplicated: [0]");
+        assert_eq!(key_map_errors(&out_of_range).unwrap(), "out of range: [15]");
+        assert_eq!(
+            key_map_errors(&(0..KEY_COUNT as u8).collect::<Vec<_>>()),
+            None
+        );
+
+        for key_map in [duplicate, out_of_range, vec![0, 1, 2]] {
+            let mut config = Config {
+                key_map,
+                ..Config::default()
+            };
+            config.validate();
+
+            assert_eq!(config.key_map, Config::default().key_map);
+        }
+
+        // A valid permutation other than the default is kept
+        let reversed: Vec<u8> = (0..KEY_COUNT as u8).rev().collect();
+        let mut config = Config {
+            key_map: reversed.clone(),
+            ..Config::default()
+        };
+        config.validate();
+        assert_eq!(config.key_map, reversed);
+    }
+}
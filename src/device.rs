@@ -1,296 +1,5121 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    panic::AssertUnwindSafe,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use data_url::DataUrl;
-use image::load_from_memory_with_format;
-use mirajazz::{device::Device, error::MirajazzError, state::DeviceStateUpdate};
-use openaction::{OUTBOUND_EVENT_MANAGER, SetImageEvent};
+use futures_lite::FutureExt;
+use image::{
+    DynamicImage, ImageError, Rgb, RgbImage, imageops::overlay, load_from_memory_with_format,
+};
+use mirajazz::{
+    device::Device,
+    error::MirajazzError,
+    state::{DeviceStateReader, DeviceStateUpdate},
+    types::ImageFormat,
+};
+use openaction::{OUTBOUND_EVENT_MANAGER, OutboundEventManager, SetImageEvent};
+use tokio::sync::{
+    Mutex, Notify, OwnedMutexGuard,
+    broadcast::{self, error::RecvError},
+};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    DEVICES, TOKENS,
-    inputs::opendeck_to_device,
-    mappings::{
-        COL_COUNT, CandidateDevice, ENCODER_COUNT, KEY_COUNT, Kind, ROW_COUNT,
-        get_image_format_for_key,
+    ANIMATIONS, BRIGHTNESS, BUTTON_STATES, CONFIG, CONNECTION_TOKENS, DEVICE_EVENT_CALLBACKS,
+    DEVICES, DISABLED, FRAME_CACHE, INPUT_EVENTS, NAMES, PENDING_FLUSHES, QUEUED_IMAGES,
+    RESTORE_IMAGES, TOKENS, TRACKER, WATCHER_TOKEN,
+    config::{AnimationUpdates, Config},
+    error::DeviceError,
+    images::{
+        EncodedFrames, broken_image, can_pass_through, decode_gif_frames, encode_image, fit_to_size,
     },
+    inputs::{InputProcessor, opendeck_to_device},
+    mappings::{CandidateDevice, Capabilities, Kind, Layout, get_image_format_for_key, hid_path},
+    metrics,
+    simulated::{SimulatedDevice, SimulatedReader},
 };
 
-/// Initializes a device and listens for events
-pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
-    log::info!("Running device task for {:?}", candidate);
+#[cfg(feature = "text")]
+use crate::DEBUG_OVERLAYS;
 
-    // Wrap in a closure so we can use `?` operator
-    let device = async || -> Result<Device, MirajazzError> {
-        log::info!("Connecting to device...");
-        let device = connect(&candidate).await?;
-        log::info!("Device connected successfully");
-
-        // Try to set brightness - some devices may not support this command
-        log::info!("Setting brightness...");
-        if let Err(e) = device.set_brightness(50).await {
-            log::warn!("Failed to set brightness (this may be normal for this device): {}", e);
-            // Continue anyway - brightness setting might not be supported
-        } else {
-            log::info!("Brightness set successfully");
+/// Operations the plugin needs from a device, so the code driving it doesn't depend on
+/// mirajazz directly and can run against [SimulatedDevice] instead of hardware
+pub trait DeviceIo: Send + Sync + 'static {
+    type Reader: DeviceReader;
+
+    fn set_brightness(&self, percent: u8)
+    -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    fn clear_button_image(&self, key: u8)
+    -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    fn clear_all_button_images(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Queues already encoded image data until the next flush
+    fn write_image(
+        &self,
+        key: u8,
+        image_data: &[u8],
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    fn flush(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    fn shutdown(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    fn keep_alive(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send;
+
+    /// Returns reader turning raw firmware input into updates with the given function,
+    /// see [DeviceHandle::reader]
+    fn get_reader(&self, process_input: InputProcessor) -> Self::Reader;
+
+    /// Encodes the image with the given JPEG quality and queues it until the next flush
+    fn set_button_image(
+        &self,
+        key: u8,
+        image_format: ImageFormat,
+        image: DynamicImage,
+        jpeg_quality: u8,
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        async move {
+            let image_data = encode_image(image_format, image, jpeg_quality).await?;
+
+            self.write_image(key, &image_data).await
         }
+    }
+}
 
-        // Try to clear all button images - some devices may not support this command
-        log::info!("Clearing all button images...");
-        if let Err(e) = device.clear_all_button_images().await {
-            log::warn!("Failed to clear all button images (this may be normal for this device): {}", e);
-            // Continue anyway - clearing might not be supported or needed
-        } else {
-            log::info!("Button images cleared successfully");
+/// Source of device state updates, see [DeviceIo::get_reader]
+pub trait DeviceReader: Send + Sync {
+    /// Waits for updates, returning none if timeout was reached first
+    fn read(
+        &self,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<Vec<DeviceStateUpdate>, MirajazzError>> + Send;
+
+    /// Returns true if presses and releases are reported as they happen, protocols before v3
+    /// report a press and a release together for every press
+    fn supports_both_states(&self) -> bool;
+}
+
+impl DeviceIo for Device {
+    type Reader = Arc<DeviceStateReader>;
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+        Device::set_brightness(self, percent).await
+    }
+
+    async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+        Device::clear_button_image(self, key).await
+    }
+
+    async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+        Device::clear_all_button_images(self).await
+    }
+
+    async fn write_image(&self, key: u8, image_data: &[u8]) -> Result<(), MirajazzError> {
+        Device::write_image(self, key, image_data).await
+    }
+
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        Device::flush(self).await
+    }
+
+    async fn shutdown(&self) -> Result<(), MirajazzError> {
+        Device::shutdown(self).await
+    }
+
+    async fn keep_alive(&self) -> Result<(), MirajazzError> {
+        Device::keep_alive(self).await
+    }
+
+    fn get_reader(&self, process_input: InputProcessor) -> Self::Reader {
+        Device::get_reader(self, process_input)
+    }
+}
+
+impl DeviceReader for Arc<DeviceStateReader> {
+    async fn read(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<DeviceStateUpdate>, MirajazzError> {
+        DeviceStateReader::read(self, timeout).await
+    }
+
+    fn supports_both_states(&self) -> bool {
+        DeviceStateReader::supports_both_states(self)
+    }
+}
+
+/// Device that is either real hardware or a simulated one, see [crate::simulated]
+pub enum DeviceBackend {
+    Real(Device),
+    Simulated(SimulatedDevice),
+}
+
+impl DeviceIo for DeviceBackend {
+    type Reader = ReaderHandle;
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.set_brightness(percent).await,
+            Self::Simulated(device) => device.set_brightness(percent).await,
         }
+    }
 
-        // Try to flush - some devices may not need this
-        log::info!("Flushing device...");
-        if let Err(e) = device.flush().await {
-            log::warn!("Failed to flush device (this may be normal for this device): {}", e);
-            // Continue anyway
-        } else {
-            log::info!("Device flushed successfully");
+    async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.clear_button_image(key).await,
+            Self::Simulated(device) => device.clear_button_image(key).await,
         }
+    }
 
-        Ok(device)
-    }()
-    .await;
+    async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.clear_all_button_images().await,
+            Self::Simulated(device) => device.clear_all_button_images().await,
+        }
+    }
 
-    let device: Device = match device {
-        Ok(device) => device,
-        Err(err) => {
-            handle_error(&candidate.id, err).await;
+    async fn write_image(&self, key: u8, image_data: &[u8]) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.write_image(key, image_data).await,
+            Self::Simulated(device) => device.write_image(key, image_data).await,
+        }
+    }
 
-            log::error!(
-                "Had error during device init, finishing device task: {:?}",
-                candidate
-            );
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.flush().await,
+            Self::Simulated(device) => device.flush().await,
+        }
+    }
 
-            return;
+    async fn shutdown(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.shutdown().await,
+            Self::Simulated(device) => device.shutdown().await,
         }
-    };
+    }
 
-    log::info!("Registering device {}", candidate.id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound
-            .register_device(
-                candidate.id.clone(),
-                candidate.kind.human_name(),
-                ROW_COUNT as u8,
-                COL_COUNT as u8,
-                ENCODER_COUNT as u8,
-                0,
-            )
-            .await
-            .unwrap();
+    async fn keep_alive(&self) -> Result<(), MirajazzError> {
+        match self {
+            Self::Real(device) => device.keep_alive().await,
+            Self::Simulated(device) => device.keep_alive().await,
+        }
     }
 
-    DEVICES.write().await.insert(candidate.id.clone(), device);
+    fn get_reader(&self, process_input: InputProcessor) -> Self::Reader {
+        match self {
+            Self::Real(device) => ReaderHandle::Real(DeviceIo::get_reader(device, process_input)),
+            Self::Simulated(device) => ReaderHandle::Simulated(device.get_reader(process_input)),
+        }
+    }
+}
 
-    tokio::select! {
-        _ = device_events_task(&candidate) => {},
-        _ = token.cancelled() => {}
-    };
+/// Reader of a [DeviceBackend]
+pub enum ReaderHandle {
+    Real(Arc<DeviceStateReader>),
+    Simulated(SimulatedReader),
+}
 
-    log::info!("Shutting down device {:?}", candidate);
+impl DeviceReader for ReaderHandle {
+    async fn read(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<DeviceStateUpdate>, MirajazzError> {
+        match self {
+            Self::Real(reader) => reader.read(timeout).await,
+            Self::Simulated(reader) => reader.read(timeout).await,
+        }
+    }
 
-    if let Some(device) = DEVICES.read().await.get(&candidate.id) {
-        device.shutdown().await.ok();
+    fn supports_both_states(&self) -> bool {
+        match self {
+            Self::Real(reader) => reader.supports_both_states(),
+            Self::Simulated(reader) => reader.supports_both_states(),
+        }
     }
+}
 
-    log::info!("Device task finished for {:?}", candidate);
+/// Connected device together with its id and kind, resolved once on connect
+pub struct DeviceHandle<D: DeviceIo = DeviceBackend> {
+    pub id: String,
+    pub backend: Arc<D>,
+    pub kind: Kind,
+    /// Window in which image updates are flushed together, see [flush_debounced]
+    flush_window: Duration,
+    /// Hash of the source image currently shown on each OpenDeck position, so images
+    /// re-sent on profile activation aren't written again, see [set_button_image_bytes]
+    image_hashes: Mutex<HashMap<u8, u64>>,
+    /// Last image set on each OpenDeck position, see [set_button_region]
+    images: Mutex<HashMap<u8, ButtonImage>>,
+    /// Lock of each OpenDeck position held while an image is written to it, see [DeviceHandle::lock_position]
+    position_locks: Mutex<HashMap<u8, Arc<Mutex<()>>>>,
 }
 
-/// Handles errors, returning true if should continue, returning false if an error is fatal
-pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
-    log::error!("Device {} error: {}", id, err);
+/// Image set on a button, as it was before being fitted and encoded for the device
+#[derive(Clone)]
+pub enum ButtonImage {
+    /// Encoded image as it was received, only decoded when it's needed
+    Encoded(Arc<[u8]>, image::ImageFormat),
+    /// Image rendered by the plugin
+    Decoded(DynamicImage),
+}
 
-    // Some errors are not critical and can be ignored without sending disconnected event
-    if matches!(err, MirajazzError::ImageError(_) | MirajazzError::BadData) {
-        return true;
+impl ButtonImage {
+    /// Returns the image decoded, the first frame for animations
+    pub fn decode(&self) -> Result<DynamicImage, ImageError> {
+        match self {
+            Self::Encoded(bytes, format) => load_from_memory_with_format(bytes, *format),
+            Self::Decoded(image) => Ok(image.clone()),
+        }
     }
+}
 
-    log::info!("Deregistering device {}", id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound.deregister_device(id.clone()).await.unwrap();
+impl<D: DeviceIo> DeviceHandle<D> {
+    pub fn new(id: String, backend: D, kind: Kind) -> Self {
+        Self {
+            image_hashes: Mutex::new(HashMap::with_capacity(kind.layout().key_count())),
+            images: Mutex::new(HashMap::with_capacity(kind.layout().key_count())),
+            position_locks: Mutex::new(HashMap::with_capacity(kind.layout().key_count())),
+            flush_window: Duration::from_millis(CONFIG.flush_debounce_ms),
+            id,
+            backend: Arc::new(backend),
+            kind,
+        }
     }
 
-    log::info!("Cancelling tasks for device {}", id);
-    if let Some(token) = TOKENS.read().await.get(id) {
-        token.cancel();
+    /// Returns reader of the device, turning its input with the [Kind::input_processor] of its kind
+    pub fn reader(&self) -> D::Reader {
+        self.backend.get_reader(self.kind.input_processor())
     }
 
-    log::info!("Removing device {} from the list", id);
-    DEVICES.write().await.remove(id);
+    /// Waits until nothing else writes to the OpenDeck position and locks it, so writes to the
+    /// same button happen in the order they were asked for and the last requested image stays
+    ///
+    /// The lock is fair, so a writer can't be overtaken by another one that asked after it.
+    /// Positions are only ever locked one at a time, or all of them in ascending order with
+    /// [DeviceHandle::lock_all_positions]
+    async fn lock_position(&self, position: u8) -> OwnedMutexGuard<()> {
+        let lock = self
+            .position_locks
+            .lock()
+            .await
+            .entry(position)
+            .or_default()
+            .clone();
 
-    log::info!("Finished clean-up for {}", id);
+        lock.lock_owned().await
+    }
 
-    false
+    /// Locks every position of the keypad, for writes that cover the whole device
+    async fn lock_all_positions(&self) -> Vec<OwnedMutexGuard<()>> {
+        let mut guards = Vec::with_capacity(self.kind.layout().key_count());
+
+        for position in 0..self.kind.layout().key_count() as u8 {
+            guards.push(self.lock_position(position).await);
+        }
+
+        guards
+    }
 }
 
-pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
-    let result = Device::connect(
-        &candidate.dev,
-        candidate.kind.protocol_version(),
-        KEY_COUNT,
-        ENCODER_COUNT,
-    )
-    .await;
+impl<D: DeviceIo> DeviceIo for DeviceHandle<D> {
+    type Reader = D::Reader;
 
-    match result {
-        Ok(device) => Ok(device),
-        Err(e) => {
-            log::error!("Error while connecting to device: {e}");
+    fn set_brightness(
+        &self,
+        percent: u8,
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.set_brightness(percent)
+    }
 
-            Err(e)
-        }
+    fn clear_button_image(
+        &self,
+        key: u8,
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.clear_button_image(key)
+    }
+
+    fn clear_all_button_images(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.clear_all_button_images()
+    }
+
+    fn write_image(
+        &self,
+        key: u8,
+        image_data: &[u8],
+    ) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.write_image(key, image_data)
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.flush()
+    }
+
+    fn shutdown(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.shutdown()
+    }
+
+    fn keep_alive(&self) -> impl Future<Output = Result<(), MirajazzError>> + Send {
+        self.backend.keep_alive()
+    }
+
+    fn get_reader(&self, process_input: InputProcessor) -> Self::Reader {
+        self.backend.get_reader(process_input)
     }
 }
 
-/// Handles events from device to OpenDeck
-async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzError> {
-    log::info!("Connecting to {} for incoming events", candidate.id);
+/// Delay before the first reconnection attempt after a device drops
+pub const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound for the delay between reconnection attempts, the delay doubles after each failure
+pub const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
 
-    let devices_lock = DEVICES.read().await;
-    let reader = match devices_lock.get(&candidate.id) {
-        Some(device) => device.get_reader(crate::inputs::process_input),
-        None => return Ok(()),
-    };
-    drop(devices_lock);
+/// Delay between reconnection attempts, doubling after each failed one up to
+/// [RECONNECT_MAX_BACKOFF]
+struct Backoff(Duration);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self(RECONNECT_INITIAL_BACKOFF)
+    }
+}
+
+impl Backoff {
+    /// Goes back to [RECONNECT_INITIAL_BACKOFF], once the device connected
+    fn reset(&mut self) {
+        self.0 = RECONNECT_INITIAL_BACKOFF;
+    }
 
-    log::info!("Connected to {} for incoming events", candidate.id);
+    /// Waits out the delay and doubles it for the next attempt, returns false if the token
+    /// was cancelled meanwhile
+    async fn wait(&mut self, token: &CancellationToken) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(self.0) => {},
+            _ = token.cancelled() => return false,
+        };
+
+        self.0 = (self.0 * 2).min(RECONNECT_MAX_BACKOFF);
 
-    log::info!("Reader is ready for {}", candidate.id);
+        true
+    }
+}
 
-    // Track last processed event to avoid duplicates
-    use std::collections::HashSet;
-    use std::time::{Duration, Instant};
-    
-    #[derive(Hash, PartialEq, Eq, Clone, Copy)]
-    enum EventKey {
-        ButtonDown(u8),
-        ButtonUp(u8),
-        EncoderDown(u8),
-        EncoderUp(u8),
-        EncoderTwist(u8, i16),
+/// Runs device init, giving up once it takes longer than `timeout`, returns None if the token
+/// was cancelled meanwhile
+///
+/// A wedged device can stall connecting or any of the init commands, it's given up on like on
+/// any other init error and tried again later
+async fn init_within<T>(
+    init: impl Future<Output = T>,
+    timeout: Duration,
+    token: &CancellationToken,
+) -> Option<Result<T, tokio::time::error::Elapsed>> {
+    tokio::select! {
+        result = tokio::time::timeout(timeout, init) => Some(result),
+        _ = token.cancelled() => None,
     }
-    
-    let mut last_events: HashSet<(EventKey, Instant)> = HashSet::new();
-    let dedup_window = Duration::from_millis(500); // 500ms window for deduplication
+}
+
+/// Initializes a device and listens for events, reconnecting with backoff if the device drops,
+/// until the token is cancelled
+pub async fn device_task(
+    candidate: CandidateDevice,
+    token: CancellationToken,
+    config: &'static Config,
+) {
+    log::info!("Running device task for {:?}", candidate);
+
+    let mut backoff = Backoff::default();
+    let mut reconnecting = false;
+    let init_timeout = Duration::from_millis(config.init_timeout_ms);
 
     loop {
-        log::info!("Reading updates...");
+        let Some(device) = init_within(init_device(&candidate, config), init_timeout, &token).await
+        else {
+            break;
+        };
+
+        match device {
+            Ok(Ok((device, failed))) => {
+                backoff.reset();
+
+                // Cancelled by `handle_error` when the connection fails, to make us reconnect
+                let connection = token.child_token();
+
+                CONNECTION_TOKENS
+                    .write()
+                    .await
+                    .insert(candidate.id.clone(), connection.clone());
+
+                log::info!(
+                    "Registering device {} at {} as {:?}",
+                    candidate.id,
+                    hid_path(&candidate.dev),
+                    candidate.display_name()
+                );
+                log_init_outcome(&candidate.id, failed);
+
+                // OpenDeck isn't going to take this device, so there's no point reconnecting
+                if !register_device(&candidate.id, candidate.display_name(), device).await {
+                    CONNECTION_TOKENS.write().await.remove(&candidate.id);
+                    token.cancel();
 
-        let updates = match reader.read(None).await {
-            Ok(updates) => updates,
-            Err(e) => {
-                if !handle_error(&candidate.id, e).await {
                     break;
                 }
 
-                continue;
+                // Init cleared the screens, which OpenDeck doesn't know about, put back what
+                // they showed right away and then ask for anything that changed meanwhile
+                let restored = restore_images(&candidate.id).await;
+                if reconnecting || restored {
+                    request_refresh(&candidate.id).await;
+                }
+
+                tokio::select! {
+                    _ = device_events_task(&candidate.id, config) => {},
+                    _ = connection.cancelled() => {}
+                };
+
+                CONNECTION_TOKENS.write().await.remove(&candidate.id);
             }
-        };
+            Ok(Err(err)) => {
+                log::error!(
+                    "Had error during device init: {}, candidate: {:?}",
+                    err,
+                    candidate
+                );
+            }
+            Err(_) => {
+                log::error!(
+                    "Device init timed out after {:?}, candidate: {:?}",
+                    init_timeout,
+                    candidate
+                );
+            }
+        }
 
-        // Clean up old events from deduplication cache
-        let now = Instant::now();
-        last_events.retain(|(_, time)| now.duration_since(*time) < dedup_window);
+        if token.is_cancelled() {
+            break;
+        }
 
-        for update in updates {
-            log::info!("New update: {:#?}", update);
+        log::info!("Reconnecting to {} in {:?}", candidate.id, backoff.0);
+        metrics::increment(&metrics::RECONNECTS);
+        reconnecting = true;
 
-            // Create a key for deduplication
-            let event_key = match &update {
-                DeviceStateUpdate::ButtonDown(key) => EventKey::ButtonDown(*key),
-                DeviceStateUpdate::ButtonUp(key) => EventKey::ButtonUp(*key),
-                DeviceStateUpdate::EncoderDown(enc) => EventKey::EncoderDown(*enc),
-                DeviceStateUpdate::EncoderUp(enc) => EventKey::EncoderUp(*enc),
-                DeviceStateUpdate::EncoderTwist(enc, val) => EventKey::EncoderTwist(*enc, *val as i16),
-            };
+        if !backoff.wait(&token).await {
+            break;
+        }
+    }
 
-            // Check for duplicates (same event type and key/encoder within the dedup window)
-            let is_duplicate = last_events.iter().any(|(key, _)| *key == event_key);
+    log::info!("Shutting down device {:?}", candidate);
 
-            if is_duplicate {
-                log::debug!("Skipping duplicate event: {:#?}", update);
-                continue;
-            }
+    remove_device(&candidate.id).await;
 
-            // Add to deduplication cache
-            last_events.insert((event_key, now));
+    // Drop our lifetime token, unless the device was plugged in again and a new task replaced it
+    let mut tokens = TOKENS.write().await;
+    if tokens.get(&candidate.id).is_some_and(|t| t.is_cancelled()) {
+        tokens.remove(&candidate.id);
+    }
+    drop(tokens);
 
-            let id = candidate.id.clone();
+    log::info!("Device task finished for {:?}", candidate);
+}
 
-            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                match update {
-                    DeviceStateUpdate::ButtonDown(key) => {
-                        log::info!("Sending key_down event: device_id={}, key={}", id, key);
-                        outbound.key_down(id.clone(), key).await.unwrap();
-                    }
-                    DeviceStateUpdate::ButtonUp(key) => {
-                        log::info!("Sending key_up event: device_id={}, key={}", id, key);
-                        outbound.key_up(id.clone(), key).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderDown(encoder) => {
-                        outbound.encoder_down(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderUp(encoder) => {
-                        outbound.encoder_up(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderTwist(encoder, val) => {
-                        outbound
-                            .encoder_change(id, encoder, val as i16)
-                            .await
-                            .unwrap();
-                    }
-                }
+/// Runs [device_task], cleaning the device up if the task panics, so a bug in it doesn't leave
+/// the device registered in OpenDeck with nothing listening to it
+pub async fn supervised_device_task(
+    candidate: CandidateDevice,
+    token: CancellationToken,
+    config: &'static Config,
+) {
+    let id = candidate.id.clone();
+
+    supervise(&id, token.clone(), device_task(candidate, token, config)).await;
+}
+
+/// Runs the task of the device with the given id and token, cleaning up after it if it panics,
+/// see [supervised_device_task]
+async fn supervise(id: &str, token: CancellationToken, task: impl Future<Output = ()>) {
+    let result = AssertUnwindSafe(task).catch_unwind().await;
+
+    let Err(panic) = result else {
+        return;
+    };
+
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    log::error!("Device task for {} panicked: {}", id, message);
+
+    // Clean up whatever the task left behind, the same way it would have on the way out
+    token.cancel();
+    CONNECTION_TOKENS.write().await.remove(id);
+    remove_device(id).await;
+
+    let mut tokens = TOKENS.write().await;
+    if tokens.get(id).is_some_and(|t| t.is_cancelled()) {
+        tokens.remove(id);
+    }
+}
+
+/// Connects to a device and runs the initialization commands, returning the device with the
+/// number of init commands that failed
+///
+/// A device that failed every init command is not returned, so it's never registered
+async fn init_device(
+    candidate: &CandidateDevice,
+    config: &Config,
+) -> Result<(DeviceHandle, usize), MirajazzError> {
+    log::info!("Connecting to device...");
+    let device = DeviceHandle::new(
+        candidate.id.clone(),
+        DeviceBackend::Real(connect(candidate).await?),
+        candidate.kind.clone(),
+    );
+    log::info!("Device connected successfully");
+
+    let brightness = initial_brightness(&candidate.id, config).await;
+    let failed = candidate.kind.init_sequence(&device, brightness).await?;
+
+    Ok((device, failed))
+}
+
+/// Returns brightness to apply on connect, the last one set from OpenDeck if the device
+/// was connected before, otherwise the one from the config
+pub async fn initial_brightness(id: &str, config: &Config) -> u8 {
+    BRIGHTNESS
+        .read()
+        .await
+        .get(id)
+        .copied()
+        .unwrap_or(config.brightness)
+}
+
+/// Number of attempts for each init command, a device that was just plugged in may not be ready yet
+pub const INIT_COMMAND_ATTEMPTS: u32 = 3;
+/// Delay between attempts of an init command
+pub const INIT_COMMAND_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Runs an init command up to [INIT_COMMAND_ATTEMPTS] times, returning the last error if none succeeded
+async fn with_retries<F, Fut>(name: &str, command: F) -> Result<(), MirajazzError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), MirajazzError>>,
+{
+    retry(
+        name,
+        INIT_COMMAND_ATTEMPTS,
+        INIT_COMMAND_RETRY_DELAY,
+        command,
+    )
+    .await
+}
+
+/// Runs a command up to the given number of times, waiting the delay between attempts,
+/// returning the last error if none succeeded
+async fn retry<F, Fut>(
+    name: &str,
+    attempts: u32,
+    delay: Duration,
+    mut command: F,
+) -> Result<(), MirajazzError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), MirajazzError>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match command().await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < attempts => {
+                log::warn!(
+                    "Attempt {}/{} to {} failed: {}, retrying in {:?}",
+                    attempt,
+                    attempts,
+                    name,
+                    err,
+                    delay
+                );
+
+                attempt += 1;
+                tokio::time::sleep(delay).await;
             }
+            Err(err) => return Err(err),
         }
     }
+}
 
-    Ok(())
+/// Number of commands run by [run_init_commands]
+pub const INIT_COMMAND_COUNT: usize = 3;
+
+/// Runs the initialization commands, failures are logged but not fatal, as some devices
+/// may not support all of them
+///
+/// Returns number of failed commands, or the last error if all of them failed
+pub async fn run_init_commands<D: DeviceIo>(
+    device: &D,
+    brightness: u8,
+) -> Result<usize, MirajazzError> {
+    let mut failed = 0;
+    let mut last_error = None;
+
+    // Try to set brightness - some devices may not support this command
+    log::info!("Setting brightness...");
+    let result = with_retries("set brightness", || device.set_brightness(brightness)).await;
+    if let Err(e) = result {
+        log::warn!("Failed to set brightness (this may be normal for this device): {}", e);
+        // Continue anyway - brightness setting might not be supported
+        failed += 1;
+        last_error = Some(e);
+    } else {
+        log::info!("Brightness set successfully");
+    }
+
+    // Try to clear all button images - some devices may not support this command
+    log::info!("Clearing all button images...");
+    let result = with_retries("clear images", || device.clear_all_button_images()).await;
+    if let Err(e) = result {
+        log::warn!("Failed to clear all button images (this may be normal for this device): {}", e);
+        // Continue anyway - clearing might not be supported or needed
+        failed += 1;
+        last_error = Some(e);
+    } else {
+        log::info!("Button images cleared successfully");
+    }
+
+    // Try to flush - some devices may not need this
+    log::info!("Flushing device...");
+    if let Err(e) = with_retries("flush", || device.flush()).await {
+        log::warn!("Failed to flush device (this may be normal for this device): {}", e);
+        // Continue anyway
+        failed += 1;
+        last_error = Some(e);
+    } else {
+        log::info!("Device flushed successfully");
+    }
+
+    match last_error {
+        Some(err) if failed == INIT_COMMAND_COUNT => Err(err),
+        _ => Ok(failed),
+    }
 }
 
-/// Handles different combinations of "set image" event, including clearing the specific buttons and whole device
-pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(), MirajazzError> {
-    match (evt.position, evt.image) {
-        (Some(position), Some(image)) => {
-            log::info!("Setting image for button {}", position);
+/// Logs the outcome of [run_init_commands] before registering the device, so a device
+/// that only partially initialized can be told apart from a healthy one
+pub fn log_init_outcome(id: &str, failed: usize) {
+    if failed > 0 {
+        log::warn!(
+            "Registering device {} in a degraded state, {} of {} init commands failed",
+            id,
+            failed,
+            INIT_COMMAND_COUNT
+        );
+    } else {
+        log::info!("Device {} initialized successfully", id);
+    }
+}
 
-            // OpenDeck sends image as a data url, so parse it using a library
-            let url = DataUrl::process(image.as_str()).unwrap(); // Isn't expected to fail, so unwrap it is
-            let (body, _fragment) = url.decode_to_vec().unwrap(); // Same here
+/// Clears the screens so no stale images are left behind and shuts the device down,
+/// failures are logged the same way as in [run_init_commands]
+pub async fn run_shutdown_commands<D: DeviceIo>(device: &D) {
+    log::info!("Clearing all button images before shutdown...");
+    if let Err(e) = device.clear_all_button_images().await {
+        log::warn!(
+            "Failed to clear all button images (this may be normal for this device): {}",
+            e
+        );
+    }
 
-            // Allow only image/jpeg mime for now
-            if url.mime_type().subtype != "jpeg" {
-                log::error!("Incorrect mime type: {}", url.mime_type());
+    if let Err(e) = device.flush().await {
+        log::warn!(
+            "Failed to flush device (this may be normal for this device): {}",
+            e
+        );
+    }
 
-                return Ok(()); // Not a fatal error, enough to just log it
-            }
+    device.shutdown().await.ok();
+}
 
-            let image = load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)?;
+/// Takes device out of the list, shuts it down and deregisters it from OpenDeck, keeping its
+/// images for [restore_images] in case it's plugged in again
+pub async fn remove_device(id: &str) {
+    stop_animations(id).await;
+    NAMES.write().await.remove(id);
+    BUTTON_STATES.write().await.remove(id);
 
-            let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap(); // Safe to unwrap here, because device is already filtered
+    let device = DEVICES.write().await.remove(id);
 
-            device
-                .set_button_image(
-                    opendeck_to_device(position),
-                    get_image_format_for_key(&kind, position),
-                    image,
-                )
-                .await?;
-            device.flush().await?;
+    if let Some(device) = device {
+        emit_device_event(DeviceEvent::Disconnected(id.to_string()));
+        keep_images(id, device.as_ref()).await;
+
+        run_shutdown_commands(device.as_ref()).await;
+
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            outbound.deregister_device(id.to_string()).await.ok();
         }
-        (Some(position), None) => {
-            device
-                .clear_button_image(opendeck_to_device(position))
-                .await?;
-            device.flush().await?;
+    }
+}
+
+/// Time [shutdown_all] waits for the device tasks to shut their devices down
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stops every device task and waits for the devices to be shut down, devices that are
+/// still around after [SHUTDOWN_TIMEOUT] are cleared directly
+pub async fn shutdown_all() {
+    // Discovery goes first, so it doesn't start device tasks that would miss the cancellation
+    if let Some(token) = WATCHER_TOKEN.lock().await.take() {
+        token.cancel();
+    }
+
+    for token in TOKENS.read().await.values() {
+        token.cancel();
+    }
+
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        while !DEVICES.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
-        (None, None) => {
-            device.clear_all_button_images().await?;
-            device.flush().await?;
+    })
+    .await;
+
+    if drained.is_err() {
+        log::warn!(
+            "Device tasks didn't finish in {:?}, clearing devices directly",
+            SHUTDOWN_TIMEOUT
+        );
+
+        let devices: Vec<_> = DEVICES
+            .read()
+            .await
+            .iter()
+            .map(|(id, device)| (id.clone(), device.clone()))
+            .collect();
+
+        for (id, device) in devices {
+            stop_animations(&id).await;
+
+            if let Err(e) = device.clear_all_button_images().await {
+                log::warn!("Failed to clear all button images of {}: {}", id, e);
+            } else if let Err(e) = device.flush().await {
+                log::warn!("Failed to flush {}: {}", id, e);
+            }
         }
-        _ => {}
     }
+}
 
-    Ok(())
+/// Registers initialized device in OpenDeck and makes it available for events,
+/// returns false and shuts the device down if OpenDeck couldn't be told about it
+pub async fn register_device(id: &str, name: String, device: DeviceHandle) -> bool {
+    let name = unique_name(id, name).await;
+    let layout = device.kind.layout();
+
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut()
+        && let Err(err) = outbound
+            .register_device(
+                id.to_string(),
+                name,
+                layout.rows as u8,
+                layout.cols as u8,
+                layout.encoders as u8,
+                0,
+            )
+            .await
+    {
+        log::error!("Failed to register device {} in OpenDeck: {}", id, err);
+
+        run_shutdown_commands(&device).await;
+
+        return false;
+    }
+
+    DEVICES
+        .write()
+        .await
+        .insert(id.to_string(), Arc::new(device));
+
+    log::info!("Connected devices: {:?}", connected_devices().await);
+    emit_device_event(DeviceEvent::Connected(id.to_string()));
+
+    if self_test_enabled() {
+        let id = id.to_string();
+
+        TRACKER.lock().await.spawn(async move {
+            if let Err(err) = run_self_test(&id).await {
+                handle_error(&id, err).await;
+            }
+        });
+    }
+
+    #[cfg(feature = "text")]
+    if debug_overlay_enabled() {
+        set_debug_overlay(id, true).await;
+    }
+
+    true
+}
+
+/// Time images of a disconnected or removed device are kept for [restore_images]
+pub const RESTORE_IMAGES_TTL: Duration = Duration::from_secs(600);
+
+/// Images of a device kept for [restore_images], with the time they were kept
+pub type KeptImages = (Instant, HashMap<u8, ButtonImage>);
+
+/// Keeps the images the device shows for [restore_images], the device comes back with its
+/// screens cleared
+async fn keep_images<D: DeviceIo>(id: &str, device: &DeviceHandle<D>) {
+    let images = std::mem::take(&mut *device.images.lock().await);
+
+    let mut kept = RESTORE_IMAGES.lock().await;
+    kept.retain(|_, (since, _)| since.elapsed() < RESTORE_IMAGES_TTL);
+    if !images.is_empty() {
+        kept.insert(id.to_string(), (Instant::now(), images));
+    }
+}
+
+/// Sets images the device showed before it lost the connection or was removed again, with a
+/// single flush at the end, animations start over
+///
+/// Returns true if there were images to put back, images kept longer than
+/// [RESTORE_IMAGES_TTL] are dropped instead
+pub async fn restore_images(id: &str) -> bool {
+    let Some((since, images)) = RESTORE_IMAGES.lock().await.remove(id) else {
+        return false;
+    };
+    if since.elapsed() >= RESTORE_IMAGES_TTL {
+        log::info!("Dropping images of {} kept since {:?}", id, since);
+        return false;
+    }
+    let Some(device) = get_device(id).await else {
+        return false;
+    };
+
+    log::info!("Restoring {} images of {}", images.len(), id);
+
+    let result = async {
+        for (position, image) in images {
+            restore_image(&device, position, image).await?;
+        }
+
+        flush_with_retries(device.as_ref()).await?;
+
+        Ok::<_, DeviceError>(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        handle_error(id, err).await;
+    }
+
+    true
+}
+
+/// Writes an image kept by [restore_images] without flushing it, GIFs go through
+/// [set_button_image_bytes] so they're animated again
+async fn restore_image<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    image: ButtonImage,
+) -> Result<(), DeviceError> {
+    if let ButtonImage::Encoded(bytes, image::ImageFormat::Gif) = &image {
+        // The button still counts as showing the GIF while something else covers it, e.g. the
+        // debug overlay, so it has to be forgotten for the GIF to be written again
+        device.image_hashes.lock().await.remove(&position);
+
+        return set_button_image_bytes(device, position, bytes, image::ImageFormat::Gif).await;
+    }
+
+    let _guard = device.lock_position(position).await;
+    let image_format = get_image_format_for_key(&device.kind, position);
+    let key = opendeck_to_device(device.kind.layout(), position);
+
+    match &image {
+        ButtonImage::Encoded(bytes, format) if can_pass_through(image_format, *format, bytes) => {
+            device.write_image(key, bytes).await?
+        }
+        _ => {
+            device
+                .set_button_image(key, image_format, image.decode()?, CONFIG.jpeg_quality)
+                .await?
+        }
+    }
+
+    device.images.lock().await.insert(position, image);
+
+    Ok(())
+}
+
+/// Asks OpenDeck to send all the button images of the device again, forgetting the images
+/// the device is known to show so none of them are skipped
+pub async fn request_refresh(id: &str) {
+    if let Some(device) = get_device(id).await {
+        device.image_hashes.lock().await.clear();
+    }
+
+    log::info!("Asking OpenDeck to re-render images of {}", id);
+
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut()
+        && let Err(err) = outbound.rerender_images(id.to_string()).await
+    {
+        log::warn!(
+            "Failed to ask OpenDeck to re-render images of {}: {}",
+            id,
+            err
+        );
+    }
+}
+
+/// Pauses or resumes the device without disconnecting it, a paused device has its screens
+/// cleared and its events dropped, resuming asks OpenDeck for the images again
+pub async fn set_device_enabled(id: &str, enabled: bool) {
+    if enabled {
+        if DISABLED.write().await.remove(id) {
+            log::info!("Enabling device {}", id);
+
+            request_refresh(id).await;
+        }
+
+        return;
+    }
+
+    if !DISABLED.write().await.insert(id.to_string()) {
+        return;
+    }
+
+    log::info!("Disabling device {}", id);
+
+    stop_animations(id).await;
+
+    let result = match get_device(id).await {
+        Some(device) => {
+            device.image_hashes.lock().await.clear();
+            device.images.lock().await.clear();
+
+            match device.clear_all_button_images().await {
+                Ok(()) => device.flush().await,
+                Err(err) => Err(err),
+            }
+        }
+        None => return,
+    };
+
+    if let Err(err) = result {
+        handle_error(id, err.into()).await;
+    }
+}
+
+/// Device becoming available or going away, passed to callbacks registered with [on_device_event]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// Device was registered in OpenDeck, with its id
+    Connected(String),
+    /// Device was taken out of the list of connected devices, with its id
+    Disconnected(String),
+}
+
+pub type DeviceEventCallback = Box<dyn Fn(DeviceEvent) + Send + Sync>;
+
+/// Registers a callback called whenever a device connects or disconnects, so host apps
+/// don't have to poll [connected_devices]
+///
+/// Callbacks run on the task that connected or dropped the device, they should return quickly
+/// and must not register other callbacks
+pub fn on_device_event(callback: impl Fn(DeviceEvent) + Send + Sync + 'static) {
+    DEVICE_EVENT_CALLBACKS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(Box::new(callback));
+}
+
+/// Calls every callback registered with [on_device_event]
+pub fn emit_device_event(event: DeviceEvent) {
+    log::debug!("Device event: {:?}", event);
+
+    for callback in DEVICE_EVENT_CALLBACKS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+    {
+        callback(event.clone());
+    }
+}
+
+/// Returns handle of a connected device, the lock on [DEVICES] is released before it's returned,
+/// so device I/O never holds it up for [handle_error] and [remove_device]
+pub async fn get_device(id: &str) -> Option<Arc<DeviceHandle>> {
+    DEVICES.read().await.get(id).cloned()
+}
+
+/// Returns pressed state of every button of the device by OpenDeck index, as last reported
+/// by the device, or none if the device isn't connected
+///
+/// Protocols before v3 report a release together with every press, so a button is never seen
+/// held and devices speaking them have no snapshot either, see [DeviceReader::supports_both_states]
+pub async fn button_snapshot(id: &str) -> Option<Vec<bool>> {
+    BUTTON_STATES.read().await.get(id).cloned()
+}
+
+/// Returns capabilities of a connected device, or none if the device isn't connected
+pub async fn device_capabilities(id: &str) -> Option<Capabilities> {
+    DEVICES
+        .read()
+        .await
+        .get(id)
+        .map(|device| device.kind.capabilities())
+}
+
+/// Returns snapshot of connected devices as pairs of device id and human-readable name
+pub async fn connected_devices() -> Vec<(String, String)> {
+    let names = NAMES.read().await;
+
+    DEVICES
+        .read()
+        .await
+        .iter()
+        .map(|(id, device)| {
+            let name = names
+                .get(id)
+                .cloned()
+                .unwrap_or_else(|| device.kind.human_name());
+
+            (id.clone(), name)
+        })
+        .collect()
+}
+
+/// Returns the name to register the device with, numbered like `Name (#2)` if another device
+/// already uses it, so identical devices can be told apart in OpenDeck
+async fn unique_name(id: &str, name: String) -> String {
+    let mut names = NAMES.write().await;
+
+    let taken: HashSet<&String> = names
+        .iter()
+        .filter(|(other, _)| other.as_str() != id)
+        .map(|(_, name)| name)
+        .collect();
+
+    let unique = if taken.contains(&name) {
+        (2..)
+            .map(|index| format!("{} (#{})", name, index))
+            .find(|candidate| !taken.contains(candidate))
+            .unwrap()
+    } else {
+        name
+    };
+
+    names.insert(id.to_string(), unique.clone());
+
+    unique
+}
+
+/// Handles errors, returning true if should continue, returning false if an error is fatal
+///
+/// Fatal errors drop the current connection, the device task then tries to reconnect
+///
+/// Locks the outbound event manager to deregister the device, see [handle_error_with_outbound]
+/// for handling errors in OpenDeck event handlers
+pub async fn handle_error(id: &str, err: DeviceError) -> bool {
+    if !log_error(id, &err) {
+        return true;
+    }
+
+    deregister_failed_device(id, OUTBOUND_EVENT_MANAGER.lock().await.as_mut()).await;
+    drop_failed_device(id).await;
+
+    false
+}
+
+/// Same as [handle_error], for OpenDeck event handlers, which run with the outbound event
+/// manager locked and get it passed in, locking it again would never return
+pub async fn handle_error_with_outbound(
+    id: &str,
+    err: DeviceError,
+    outbound: &mut OutboundEventManager,
+) -> bool {
+    if !log_error(id, &err) {
+        return true;
+    }
+
+    deregister_failed_device(id, Some(outbound)).await;
+    drop_failed_device(id).await;
+
+    false
+}
+
+/// Logs the error, returning true if it's fatal, some errors are not critical and can be
+/// ignored without sending disconnected event
+fn log_error(id: &str, err: &DeviceError) -> bool {
+    log::error!("Device {} error: {}", id, err);
+    metrics::increment(&metrics::ERRORS);
+
+    err.is_fatal()
+}
+
+async fn deregister_failed_device(id: &str, outbound: Option<&mut impl Outbound>) {
+    log::info!("Deregistering device {}", id);
+
+    if let Some(outbound) = outbound
+        && let Err(err) = outbound.deregister_device(id.to_string()).await
+    {
+        log::warn!("Failed to deregister device {} from OpenDeck: {}", id, err);
+    }
+}
+
+/// Drops the connection of a failed device and takes it out of the list, keeping its images
+/// for the reconnect
+async fn drop_failed_device(id: &str) {
+    log::info!("Dropping connection for device {}", id);
+    if let Some(connection) = CONNECTION_TOKENS.read().await.get(id) {
+        connection.cancel();
+    }
+
+    log::info!("Removing device {} from the list", id);
+    stop_animations(id).await;
+    BUTTON_STATES.write().await.remove(id);
+    let device = DEVICES.write().await.remove(id);
+    if let Some(device) = device {
+        emit_device_event(DeviceEvent::Disconnected(id.to_string()));
+        keep_images(id, device.as_ref()).await;
+    }
+
+    log::info!("Finished clean-up for {}", id);
+}
+
+/// Sets brightness of a connected device, value range is 0 - 100
+///
+/// Errors are returned rather than handled, so OpenDeck event handlers can pass them to
+/// [handle_error_with_outbound]
+pub async fn set_device_brightness(id: &str, value: u8) -> Result<(), DeviceError> {
+    let Some(device) = get_device(id).await else {
+        log::error!("Received event for unknown device: {}", id);
+
+        return Ok(());
+    };
+
+    handle_set_brightness(device.as_ref(), value).await?;
+
+    BRIGHTNESS
+        .write()
+        .await
+        .insert(id.to_string(), value.min(100));
+
+    Ok(())
+}
+
+/// Returns brightness of a connected device, or none if the device isn't connected
+///
+/// This is the last value commanded from OpenDeck, or the one from the config if there was none,
+/// the device can't report its brightness, and dimming while idle isn't reflected here
+pub async fn get_device_brightness(id: &str) -> Option<u8> {
+    if !DEVICES.read().await.contains_key(id) {
+        return None;
+    }
+
+    Some(initial_brightness(id, &CONFIG).await)
+}
+
+/// Handles "set brightness" event, clamping the value to 0 - 100
+pub async fn handle_set_brightness<D: DeviceIo>(
+    device: &D,
+    value: u8,
+) -> Result<(), MirajazzError> {
+    let value = if value > 100 {
+        log::warn!("Brightness is out of range (0 - 100): {}, clamping", value);
+
+        100
+    } else {
+        value
+    };
+
+    device.set_brightness(value).await
+}
+
+/// Returns protocol version to connect to the device with, `force_protocol_version` from the
+/// config if set, else the one of its kind
+fn protocol_version(id: &str, kind: &Kind, config: &Config) -> usize {
+    match config.force_protocol_version {
+        Some(version) => {
+            log::info!(
+                "Connecting to {} with protocol version {} forced by config",
+                id,
+                version
+            );
+
+            version
+        }
+        None => {
+            let version = kind.protocol_version();
+            log::info!("Connecting to {} with protocol version {}", id, version);
+
+            version
+        }
+    }
+}
+
+/// Opens the device with the protocol version of its kind, unless the config forces another one
+pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
+    let protocol_version = protocol_version(&candidate.id, &candidate.kind, &CONFIG);
+
+    let layout = candidate.kind.layout();
+    let result = Device::connect(
+        &candidate.dev,
+        protocol_version,
+        layout.key_count(),
+        layout.encoders,
+    )
+    .await;
+
+    match result {
+        // Enumeration may be stale by the time we connect, so make sure it's still the same device
+        Ok(device) if !reports_kind(&candidate.kind, device.vid, device.pid) => {
+            log::error!(
+                "Device at {} reports {:04x}:{:04x}, which isn't {}, refusing to connect",
+                hid_path(&candidate.dev),
+                device.vid,
+                device.pid,
+                candidate.kind.human_name()
+            );
+
+            Err(MirajazzError::InvalidDeviceError)
+        }
+        Ok(device) => Ok(device),
+        Err(e) => {
+            log::error!("Error while connecting to device: {e}");
+
+            Err(e)
+        }
+    }
+}
+
+/// Returns true if the VID/PID the connected device reports belong to the expected kind
+fn reports_kind(kind: &Kind, vid: u16, pid: u16) -> bool {
+    Kind::from_vid_pid(vid, pid).as_ref() == Some(kind)
+}
+
+/// How long to wait for more reports right after connecting before considering the device settled
+const CONNECT_SETTLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Drains reports that arrive right after connecting and returns buttons that were held at that time,
+/// together with the other updates drained, to be handled like any others
+///
+/// Presses still held when the device settles aren't forwarded to OpenDeck, as they describe the
+/// state the device was in before we connected rather than fresh presses, and neither are releases
+/// of presses from before connecting. Presses released within the window were made after connecting
+/// and are passed on. Protocols before v3 report a release together with every press, so a held
+/// button can't be seen and nothing is drained for them
+async fn settle_held_buttons<R: DeviceReader>(
+    reader: &R,
+    layout: Layout,
+) -> (HashSet<u8>, Vec<DeviceStateUpdate>) {
+    let mut held = HashSet::new();
+    let mut drained = vec![];
+
+    if !reader.supports_both_states() {
+        return (held, drained);
+    }
+
+    // Bound the number of reads, so a chatty device can't keep us here forever
+    for _ in 0..layout.key_count() {
+        let updates = match reader.read(Some(CONNECT_SETTLE_TIMEOUT)).await {
+            Ok(updates) if !updates.is_empty() => updates,
+            _ => break,
+        };
+
+        for update in updates {
+            match update {
+                DeviceStateUpdate::ButtonDown(key) => {
+                    held.insert(key);
+                }
+                DeviceStateUpdate::ButtonUp(key) => {
+                    if held.remove(&key) {
+                        drained.push(DeviceStateUpdate::ButtonDown(key));
+                        drained.push(update);
+                    }
+                }
+                _ => drained.push(update),
+            }
+        }
+    }
+
+    (held, drained)
+}
+
+/// Number of heartbeats in a row that may fail before the device is treated as failed
+pub const WATCHDOG_MAX_FAILED_HEARTBEATS: u32 = 3;
+
+/// Sends a keep-alive to the device, which fails if the link is dead
+async fn heartbeat(id: &str) -> Result<(), MirajazzError> {
+    match get_device(id).await {
+        Some(device) => device.keep_alive().await,
+        None => Ok(()),
+    }
+}
+
+/// Dimming state of a device read by [device_events_task]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dim {
+    Awake,
+    /// Dimmed after `idle_timeout_ms` without input, any input brings it back
+    Idle,
+    /// Dimmed with `screensaver_key`, only the key brings it back, the idle timer is stopped
+    Manual,
+}
+
+/// Dims the device to `idle_brightness`, for [Dim::Idle] and [Dim::Manual]
+async fn dim_device(id: &str, config: &Config) {
+    log::debug!("Dimming {} to {}", id, config.idle_brightness);
+
+    if let Err(err) = apply_brightness(id, config.idle_brightness).await {
+        log::warn!("Failed to dim {}: {}", id, err);
+    }
+}
+
+/// Puts back brightness of a dimmed device
+async fn wake_device(id: &str, config: &Config) {
+    let brightness = initial_brightness(id, config).await;
+    log::debug!("Restoring brightness {} of {}", brightness, id);
+
+    if let Err(err) = apply_brightness(id, brightness).await {
+        log::warn!("Failed to restore brightness of {}: {}", id, err);
+    }
+}
+
+/// Sets brightness of the device without recording it in [BRIGHTNESS], for temporary changes
+async fn apply_brightness(id: &str, value: u8) -> Result<(), MirajazzError> {
+    match get_device(id).await {
+        Some(device) => device.set_brightness(value).await,
+        None => Ok(()),
+    }
+}
+
+/// Number of updates that may wait to be forwarded to OpenDeck, see [device_events_task]
+pub const EVENT_QUEUE_CAPACITY: usize = 64;
+/// Number of updates kept for every subscriber of [subscribe_events], the oldest ones are
+/// dropped for a subscriber that falls further behind
+pub const INPUT_EVENTS_CAPACITY: usize = 256;
+
+/// Subscribes to input of every device, as `(device id, update)` pairs, for frontends other
+/// than OpenDeck
+///
+/// Updates are the ones forwarded to OpenDeck, after deduplication and debouncing, with
+/// twists as the device reports them, before `encoder_sensitivity` is applied. Sending
+/// never waits for subscribers, see [recv_event] for receiving while skipping lost updates
+pub fn subscribe_events() -> broadcast::Receiver<(String, DeviceStateUpdate)> {
+    INPUT_EVENTS.subscribe()
+}
+
+/// Receives the next update from [subscribe_events], logging and skipping the updates
+/// dropped because the subscriber fell behind
+///
+/// Returns `None` only once the sender is dropped, which doesn't happen while the plugin runs
+pub async fn recv_event(
+    receiver: &mut broadcast::Receiver<(String, DeviceStateUpdate)>,
+) -> Option<(String, DeviceStateUpdate)> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(count)) => {
+                log::warn!(
+                    "Event subscriber fell behind, {} updates were dropped",
+                    count
+                );
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Returns the update described on a single line for the logs, e.g. `key 3 down`
+fn describe_update(update: &DeviceStateUpdate) -> String {
+    match *update {
+        DeviceStateUpdate::ButtonDown(key) => format!("key {} down", key),
+        DeviceStateUpdate::ButtonUp(key) => format!("key {} up", key),
+        DeviceStateUpdate::EncoderDown(enc) => format!("encoder {} down", enc),
+        DeviceStateUpdate::EncoderUp(enc) => format!("encoder {} up", enc),
+        DeviceStateUpdate::EncoderTwist(enc, delta) => {
+            format!("encoder {} twisted by {}", enc, delta)
+        }
+    }
+}
+
+/// Button or encoder event, as told apart by deduplication and the [EventQueue]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+enum EventKey {
+    ButtonDown(u8),
+    ButtonUp(u8),
+    EncoderDown(u8),
+    EncoderUp(u8),
+}
+
+impl EventKey {
+    /// Returns key of the update, twists have none as every one of them adds to the ones before
+    fn of(update: &DeviceStateUpdate) -> Option<Self> {
+        match *update {
+            DeviceStateUpdate::ButtonDown(key) => Some(Self::ButtonDown(key)),
+            DeviceStateUpdate::ButtonUp(key) => Some(Self::ButtonUp(key)),
+            DeviceStateUpdate::EncoderDown(enc) => Some(Self::EncoderDown(enc)),
+            DeviceStateUpdate::EncoderUp(enc) => Some(Self::EncoderUp(enc)),
+            DeviceStateUpdate::EncoderTwist(..) => None,
+        }
+    }
+
+    /// Returns the event that ends this one, or the one this one ends
+    fn opposite(self) -> Self {
+        match self {
+            Self::ButtonDown(key) => Self::ButtonUp(key),
+            Self::ButtonUp(key) => Self::ButtonDown(key),
+            Self::EncoderDown(enc) => Self::EncoderUp(enc),
+            Self::EncoderUp(enc) => Self::EncoderDown(enc),
+        }
+    }
+
+    const fn is_release(self) -> bool {
+        matches!(self, Self::ButtonUp(_) | Self::EncoderUp(_))
+    }
+}
+
+/// Updates of a device waiting to be sent to OpenDeck by [forward_events], up to
+/// [EVENT_QUEUE_CAPACITY] of them
+///
+/// A full queue makes room by dropping its oldest press or twist, rather than the update that
+/// just arrived. Releases are never dropped on their own, as OpenDeck may already know about
+/// their press, and a dropped press takes its release with it, so no button is left held
+#[derive(Default)]
+struct EventQueue {
+    state: std::sync::Mutex<EventQueueState>,
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct EventQueueState {
+    updates: VecDeque<DeviceStateUpdate>,
+    /// Releases to drop once they arrive, as their presses were dropped
+    orphaned: HashSet<EventKey>,
+    closed: bool,
+}
+
+impl EventQueue {
+    fn state(&self) -> std::sync::MutexGuard<'_, EventQueueState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues the update and returns the updates dropped to make room for it
+    fn push(&self, update: DeviceStateUpdate) -> Vec<DeviceStateUpdate> {
+        let mut state = self.state();
+        let mut dropped = vec![];
+
+        if let Some(key) = EventKey::of(&update)
+            && key.is_release()
+            && state.orphaned.remove(&key)
+        {
+            dropped.push(update);
+
+            return dropped;
+        }
+
+        // Only ever releases left if nothing is found, those are bounded by the number of
+        // buttons and encoders, so the queue is let grow for them
+        if state.updates.len() >= EVENT_QUEUE_CAPACITY
+            && let Some(index) = state
+                .updates
+                .iter()
+                .position(|update| !EventKey::of(update).is_some_and(EventKey::is_release))
+            && let Some(oldest) = state.updates.remove(index)
+        {
+            if let Some(release) = EventKey::of(&oldest).map(EventKey::opposite) {
+                let queued_release = state
+                    .updates
+                    .iter()
+                    .skip(index)
+                    .position(|update| EventKey::of(update) == Some(release));
+
+                match queued_release.and_then(|offset| state.updates.remove(index + offset)) {
+                    Some(release) => dropped.push(release),
+                    None => {
+                        state.orphaned.insert(release);
+                    }
+                }
+            }
+
+            dropped.insert(0, oldest);
+        }
+
+        state.updates.push_back(update);
+        drop(state);
+
+        self.ready.notify_one();
+
+        dropped
+    }
+
+    /// Waits for updates and takes every queued one, returns none once the queue is closed
+    async fn recv_all(&self) -> Option<Vec<DeviceStateUpdate>> {
+        loop {
+            let ready = self.ready.notified();
+
+            {
+                let mut state = self.state();
+
+                if !state.updates.is_empty() {
+                    return Some(state.updates.drain(..).collect());
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            ready.await;
+        }
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.ready.notify_one();
+    }
+}
+
+/// Queueing side of an [EventQueue], which closes the queue once dropped
+struct EventSender(Arc<EventQueue>);
+
+impl EventSender {
+    fn push(&self, update: DeviceStateUpdate) -> Vec<DeviceStateUpdate> {
+        self.0.push(update)
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Handles events from device to OpenDeck
+pub async fn device_events_task(id: &str, config: &'static Config) -> Result<(), MirajazzError> {
+    log::info!("Connecting to {} for incoming events", id);
+
+    let (reader, layout) = match get_device(id).await {
+        Some(device) => (device.reader(), device.kind.layout()),
+        None => return Ok(()),
+    };
+
+    log::info!("Connected to {} for incoming events", id);
+
+    log::info!("Reader is ready for {}", id);
+
+    // Protocols before v3 report a release together with every press
+    let both_states = reader.supports_both_states();
+
+    // Closed when this task ends, which ends the forwarding task as well
+    let queue = Arc::new(EventQueue::default());
+    let sender = EventSender(queue.clone());
+    TRACKER
+        .lock()
+        .await
+        .spawn(forward_events(id.to_string(), queue, config));
+
+    // Buttons that were already down when we connected, their presses are suppressed until the first release
+    let (mut held_at_connect, drained) = settle_held_buttons(&reader, layout).await;
+    let mut drained = Some(drained).filter(|updates| !updates.is_empty());
+
+    if !held_at_connect.is_empty() {
+        log::info!(
+            "Buttons held while connecting, ignoring them until released: {:?}",
+            held_at_connect
+        );
+    }
+
+    // Only followed if the device reports releases as they happen, see [button_snapshot]
+    let mut pressed = vec![false; layout.key_count()];
+    for &key in &held_at_connect {
+        if let Some(state) = pressed.get_mut(key as usize) {
+            *state = true;
+        }
+    }
+    if both_states {
+        BUTTON_STATES
+            .write()
+            .await
+            .insert(id.to_string(), pressed.clone());
+    }
+
+    // Track last processed event to avoid duplicates, twists are never deduplicated as every
+    // one of them adds to the ones before. Not kept at all with deduplication turned off
+    let mut last_events: Option<HashMap<EventKey, Instant>> =
+        config.dedup_enabled.then(HashMap::new);
+    let button_dedup_window = Duration::from_millis(config.dedup_window_ms);
+    let encoder_dedup_window = Duration::from_millis(config.encoder_dedup_window_ms);
+    let dedup_window = |key: &EventKey| match key {
+        EventKey::ButtonDown(_) | EventKey::ButtonUp(_) => button_dedup_window,
+        EventKey::EncoderDown(_) | EventKey::EncoderUp(_) => encoder_dedup_window,
+    };
+
+    // Press right after a release of the same button is contact bounce, it's dropped together
+    // with the release following it, so the bounce doesn't register as another press
+    let bounce_window = Duration::from_millis(config.bounce_window_ms);
+    let mut last_release: HashMap<u8, Instant> = HashMap::new();
+    let mut bouncing: HashSet<u8> = HashSet::new();
+
+    // Reads time out after the watchdog interval, so a link that went silent can be detected
+    let watchdog_interval = (config.watchdog_interval_ms > 0)
+        .then(|| Duration::from_millis(config.watchdog_interval_ms));
+    let mut last_activity = Instant::now();
+    let mut failed_heartbeats = 0;
+
+    // Device is dimmed after a while without input and restored on the next one, or dimmed
+    // with the screensaver key until it's pressed again
+    let idle_timeout =
+        (config.idle_timeout_ms > 0).then(|| Duration::from_millis(config.idle_timeout_ms));
+    let mut last_input = Instant::now();
+    let mut dim = Dim::Awake;
+
+    loop {
+        log::trace!("Reading updates...");
+
+        // Wake up for whichever comes first, the heartbeat or dimming of the idle device
+        let idle_remaining = idle_timeout
+            .filter(|_| dim == Dim::Awake)
+            .map(|timeout| timeout.saturating_sub(last_input.elapsed()));
+        let timeout = match (watchdog_interval, idle_remaining) {
+            (Some(interval), Some(remaining)) => Some(interval.min(remaining)),
+            (interval, remaining) => interval.or(remaining),
+        };
+
+        // Updates drained while settling are handled first
+        let read = match drained.take() {
+            Some(updates) => Ok(updates),
+            None => reader.read(timeout).await,
+        };
+
+        let updates = match read {
+            Ok(updates) => updates,
+            Err(e) => {
+                if !handle_error(id, e.into()).await {
+                    break;
+                }
+
+                continue;
+            }
+        };
+
+        if !updates.is_empty() {
+            last_activity = Instant::now();
+            last_input = last_activity;
+            failed_heartbeats = 0;
+
+            let toggled = config.screensaver_key.is_some_and(|key| {
+                updates
+                    .iter()
+                    .any(|update| matches!(update, DeviceStateUpdate::ButtonDown(k) if *k == key))
+            });
+
+            // Screensaver key dismisses either kind of dimming, any other input only the idle one
+            match (dim, toggled) {
+                (Dim::Awake, true) => {
+                    log::debug!("Screensaver key pressed on {}, dimming", id);
+
+                    dim = Dim::Manual;
+                    dim_device(id, config).await;
+                }
+                (Dim::Idle | Dim::Manual, true) | (Dim::Idle, false) => {
+                    log::debug!("Input on dimmed {}, restoring brightness", id);
+
+                    dim = Dim::Awake;
+                    wake_device(id, config).await;
+                }
+                (Dim::Awake | Dim::Manual, false) => {}
+            }
+        } else if let Some(timeout) = idle_timeout
+            && dim == Dim::Awake
+            && last_input.elapsed() >= timeout
+        {
+            log::debug!("No input on {} for {:?}, dimming", id, timeout);
+
+            // Marked as dimmed even if it failed, so it isn't retried on every read
+            dim = Dim::Idle;
+            dim_device(id, config).await;
+
+            continue;
+        } else if let Some(interval) = watchdog_interval
+            && last_activity.elapsed() >= interval
+        {
+            // Nothing was read for a while, which is normal for an idle device, so only treat
+            // the device as failed if it also stops accepting heartbeats
+            match heartbeat(id).await {
+                Ok(()) => {
+                    last_activity = Instant::now();
+                    failed_heartbeats = 0;
+                }
+                Err(err) => {
+                    failed_heartbeats += 1;
+
+                    log::warn!(
+                        "Heartbeat {}/{} to {} failed: {}",
+                        failed_heartbeats,
+                        WATCHDOG_MAX_FAILED_HEARTBEATS,
+                        id,
+                        err
+                    );
+
+                    if failed_heartbeats >= WATCHDOG_MAX_FAILED_HEARTBEATS {
+                        failed_heartbeats = 0;
+
+                        if !handle_error(id, err.into()).await {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            continue;
+        }
+
+        // Clean up old events from deduplication cache
+        let now = Instant::now();
+        if let Some(last_events) = last_events.as_mut() {
+            last_events.retain(|key, time| now.duration_since(*time) < dedup_window(key));
+        }
+
+        let mut pending = Vec::with_capacity(updates.len());
+        let mut pressed_changed = false;
+
+        for update in updates {
+            log::trace!("New update: {}", describe_update(&update));
+
+            // Snapshot follows the buttons as they are, before any filtering
+            if let DeviceStateUpdate::ButtonDown(key) | DeviceStateUpdate::ButtonUp(key) = update
+                && let Some(state) = pressed.get_mut(key as usize)
+            {
+                *state = matches!(update, DeviceStateUpdate::ButtonDown(_));
+                pressed_changed = true;
+            }
+
+            // Screensaver key is handled above, OpenDeck never sees it
+            if let DeviceStateUpdate::ButtonDown(key) | DeviceStateUpdate::ButtonUp(key) = update
+                && config.screensaver_key == Some(key)
+            {
+                continue;
+            }
+
+            match update {
+                DeviceStateUpdate::ButtonDown(key) if held_at_connect.contains(&key) => {
+                    log::debug!("Skipping press of button {} held since connect", key);
+                    continue;
+                }
+                DeviceStateUpdate::ButtonUp(key) if held_at_connect.remove(&key) => {
+                    log::debug!("Button {} held since connect was released", key);
+                    continue;
+                }
+                _ => {}
+            }
+
+            match update {
+                DeviceStateUpdate::ButtonDown(key)
+                    if last_release
+                        .get(&key)
+                        .is_some_and(|time| now.duration_since(*time) < bounce_window) =>
+                {
+                    log::debug!("Skipping bounce of button {}", key);
+                    bouncing.insert(key);
+                    continue;
+                }
+                DeviceStateUpdate::ButtonUp(key) => {
+                    last_release.insert(key, now);
+
+                    if bouncing.remove(&key) {
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            // Create a key for deduplication
+            let event_key = EventKey::of(&update);
+
+            // Check for duplicates (same event type and key/encoder within the dedup window),
+            // a repeated event refreshes the stored timestamp instead of adding another entry
+            //
+            // Updates of a batch share the time they were read at, so time can't tell two presses
+            // apart, a release in between can: it ends the press, so the next press is a new one
+            // even within the window, only a press reported again without a release is a duplicate.
+            // Protocols reporting the release together with every press can't tell them apart
+            // that way, there a press of the same button within the window is a duplicate
+            if let Some(last_events) = last_events.as_mut()
+                && let Some(event_key) = event_key
+            {
+                if last_events.insert(event_key, now).is_some() {
+                    log::debug!("Skipping duplicate event: {}", describe_update(&update));
+                    metrics::increment(&metrics::EVENTS_DEDUPED);
+                    continue;
+                }
+
+                if both_states {
+                    last_events.remove(&event_key.opposite());
+                }
+            }
+
+            pending.push(update);
+        }
+
+        if pressed_changed && both_states {
+            BUTTON_STATES
+                .write()
+                .await
+                .insert(id.to_string(), pressed.clone());
+        }
+
+        if pending.is_empty() {
+            continue;
+        }
+
+        // Paused device is still read, so its buffer doesn't fill up, but nothing is forwarded
+        if DISABLED.read().await.contains(id) {
+            log::debug!(
+                "Device {} is disabled, dropping {} updates",
+                id,
+                pending.len()
+            );
+            continue;
+        }
+
+        // Reading must not wait for OpenDeck, or the device's own buffer may overflow,
+        // so updates are only queued here, see [EventQueue] for what a full queue drops
+        for update in pending {
+            // Fails only when nobody subscribed, and laggards lose their oldest updates instead
+            // of holding up the device
+            let _ = INPUT_EVENTS.send((id.to_string(), update));
+
+            for dropped in sender.push(update) {
+                log::warn!("Event queue of {} is full, dropping {:?}", id, dropped);
+                metrics::increment(&metrics::EVENTS_DROPPED);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Error of sending an event to OpenDeck, only ever logged
+type OutboundError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Events the plugin sends to OpenDeck, so forwarding doesn't depend on the websocket
+/// behind [OutboundEventManager] and can run against a stand-in in tests
+trait Outbound: Send {
+    fn key_down(
+        &mut self,
+        id: String,
+        key: u8,
+    ) -> impl Future<Output = Result<(), OutboundError>> + Send;
+
+    fn key_up(
+        &mut self,
+        id: String,
+        key: u8,
+    ) -> impl Future<Output = Result<(), OutboundError>> + Send;
+
+    fn encoder_down(
+        &mut self,
+        id: String,
+        encoder: u8,
+    ) -> impl Future<Output = Result<(), OutboundError>> + Send;
+
+    fn encoder_up(
+        &mut self,
+        id: String,
+        encoder: u8,
+    ) -> impl Future<Output = Result<(), OutboundError>> + Send;
+
+    fn encoder_change(
+        &mut self,
+        id: String,
+        encoder: u8,
+        ticks: i16,
+    ) -> impl Future<Output = Result<(), OutboundError>> + Send;
+
+    fn deregister_device(
+        &mut self,
+        id: String,
+    ) -> impl Future<Output = Result<(), OutboundError>> + Send;
+}
+
+impl Outbound for OutboundEventManager {
+    async fn key_down(&mut self, id: String, key: u8) -> Result<(), OutboundError> {
+        Ok(OutboundEventManager::key_down(self, id, key).await?)
+    }
+
+    async fn key_up(&mut self, id: String, key: u8) -> Result<(), OutboundError> {
+        Ok(OutboundEventManager::key_up(self, id, key).await?)
+    }
+
+    async fn encoder_down(&mut self, id: String, encoder: u8) -> Result<(), OutboundError> {
+        Ok(OutboundEventManager::encoder_down(self, id, encoder).await?)
+    }
+
+    async fn encoder_up(&mut self, id: String, encoder: u8) -> Result<(), OutboundError> {
+        Ok(OutboundEventManager::encoder_up(self, id, encoder).await?)
+    }
+
+    async fn encoder_change(
+        &mut self,
+        id: String,
+        encoder: u8,
+        ticks: i16,
+    ) -> Result<(), OutboundError> {
+        Ok(OutboundEventManager::encoder_change(self, id, encoder, ticks).await?)
+    }
+
+    async fn deregister_device(&mut self, id: String) -> Result<(), OutboundError> {
+        Ok(OutboundEventManager::deregister_device(self, id).await?)
+    }
+}
+
+/// Sends updates queued by [device_events_task] to OpenDeck, until the queue is closed
+async fn forward_events(id: String, queue: Arc<EventQueue>, config: &'static Config) {
+    // Fractions of a step each encoder was twisted by, see [Config::encoder_delta]
+    let mut remainders: HashMap<u8, f32> = HashMap::new();
+
+    while let Some(pending) = queue.recv_all().await {
+        // Forward everything queued so far under a single guard, so a burst of events doesn't
+        // queue up on the lock behind image updates once per event
+        log::debug!(
+            "Forwarding {} updates from {} under one lock",
+            pending.len(),
+            id
+        );
+
+        if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+            send_updates(outbound, &id, pending, &mut remainders, config).await;
+        }
+    }
+}
+
+/// Sends updates of the device to OpenDeck one by one, logging the ones that fail
+async fn send_updates(
+    outbound: &mut impl Outbound,
+    id: &str,
+    pending: Vec<DeviceStateUpdate>,
+    remainders: &mut HashMap<u8, f32>,
+    config: &Config,
+) {
+    for update in pending {
+        let id = id.to_string();
+
+        let result = match update {
+            DeviceStateUpdate::ButtonDown(key) => {
+                log::debug!("Sending key_down event: device_id={}, key={}", id, key);
+                outbound.key_down(id.clone(), key).await
+            }
+            DeviceStateUpdate::ButtonUp(key) => {
+                log::debug!("Sending key_up event: device_id={}, key={}", id, key);
+                outbound.key_up(id.clone(), key).await
+            }
+            DeviceStateUpdate::EncoderDown(encoder) => {
+                outbound.encoder_down(id.clone(), encoder).await
+            }
+            DeviceStateUpdate::EncoderUp(encoder) => outbound.encoder_up(id.clone(), encoder).await,
+            DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                let delta = config.encoder_delta(val, remainders.entry(encoder).or_default());
+
+                // Low sensitivity takes several twists to add up to a step
+                if delta == 0 {
+                    log::debug!("Keeping twist of {} until it adds up to a step", val);
+                    continue;
+                }
+
+                outbound.encoder_change(id.clone(), encoder, delta).await
+            }
+        };
+
+        // A lost event only affects that single press, so keep forwarding
+        match result {
+            Ok(()) => metrics::increment(&metrics::EVENTS_FORWARDED),
+            Err(err) => log::error!(
+                "Failed to send {:?} from {} to OpenDeck: {}",
+                update,
+                id,
+                err
+            ),
+        }
+    }
+}
+
+/// Checks that OpenDeck position is within the keypad
+fn check_position(layout: Layout, position: u8) -> Result<(), DeviceError> {
+    if (position as usize) < layout.key_count() {
+        Ok(())
+    } else {
+        Err(DeviceError::PositionOutOfRange(position))
+    }
+}
+
+/// Handles different combinations of "set image" event, including clearing the specific buttons and whole device
+///
+/// Malformed images are returned as non-fatal errors, see [DeviceError::is_fatal], positions
+/// outside the keypad are logged and ignored
+///
+/// The event carries no state index, OpenDeck picks the image of the current state of
+/// multi-state buttons itself, so a state change arrives as just another image for the position
+///
+/// It carries no orientation either, so images are always turned by the rotation of the device,
+/// see [get_image_format_for_key]
+pub async fn handle_set_image<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    evt: SetImageEvent,
+) -> Result<(), DeviceError> {
+    // Screens of a paused device stay blank, the images are asked for again once it's enabled
+    if DISABLED.read().await.contains(&device.id) {
+        log::debug!("Device {} is disabled, ignoring image", device.id);
+
+        return Ok(());
+    }
+
+    if let Some(position) = evt.position
+        && let Err(err) = check_position(device.kind.layout(), position)
+    {
+        log::warn!("Ignoring image for device {}: {}", device.id, err);
+
+        return Ok(());
+    }
+
+    match (evt.position, evt.image) {
+        (Some(position), Some(image)) => {
+            log::debug!("Setting image for button {}", position);
+
+            // OpenDeck sends image as a data url, so parse it using a library
+            let url = DataUrl::process(image.as_str())?;
+            let (body, _fragment) = url.decode_to_vec()?;
+
+            let format = match url.mime_type().subtype.as_str() {
+                "jpeg" => image::ImageFormat::Jpeg,
+                "png" => image::ImageFormat::Png,
+                "bmp" => image::ImageFormat::Bmp,
+                "gif" => image::ImageFormat::Gif,
+                #[cfg(feature = "webp")]
+                "webp" => image::ImageFormat::WebP,
+                _ => {
+                    log::warn!(
+                        "Unrecognized mime type {}, guessing format from the data",
+                        url.mime_type()
+                    );
+
+                    image::guess_format(body.as_slice())?
+                }
+            };
+
+            if hold_back_for_animation(
+                &device.id,
+                position,
+                &body,
+                format,
+                CONFIG.animation_updates(),
+            )
+            .await
+            {
+                return Ok(());
+            }
+
+            set_button_image_bytes(device, position, body.as_slice(), format).await?;
+        }
+        (Some(position), None) => {
+            clear_buttons(device, &[position]).await?;
+        }
+        (None, None) => {
+            let _guards = device.lock_all_positions().await;
+
+            stop_animations(&device.id).await;
+            device.clear_all_button_images().await?;
+            device.image_hashes.lock().await.clear();
+            device.images.lock().await.clear();
+            flush_debounced(device).await?;
+            metrics::increment(&metrics::IMAGES_CLEARED);
+        }
+        (None, Some(_)) => {
+            // There's no button to put it on, and guessing one would overwrite a button's image
+            log::warn!(
+                "Received image without position for device {}, ignoring",
+                device.id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets image of the button at OpenDeck position from encoded image bytes and flushes it,
+/// for callers that have the image at hand without wrapping it in a data url
+pub async fn set_button_image_bytes<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    bytes: &[u8],
+    format: image::ImageFormat,
+) -> Result<(), DeviceError> {
+    check_position(device.kind.layout(), position)?;
+
+    // Held until the image is written and recorded, so the check against the last image and
+    // the write can't interleave with another write to the button
+    let _guard = device.lock_position(position).await;
+
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    if device.image_hashes.lock().await.get(&position) == Some(&hash) {
+        log::debug!("Button {} already shows this image, skipping", position);
+
+        return Ok(());
+    }
+
+    stop_animation(&device.id, position).await;
+
+    let key = opendeck_to_device(device.kind.layout(), position);
+    let image_format = get_image_format_for_key(&device.kind, position);
+
+    if format == image::ImageFormat::Gif {
+        let cache_key = frame_cache_key(hash, image_format);
+        let cached = FRAME_CACHE.lock().await.get(cache_key);
+
+        let frames = match cached {
+            Some(frames) => Some(frames),
+            None => {
+                let frames = match decode_gif_frames(bytes) {
+                    Ok(frames) => frames,
+                    Err(err) => return Err(show_broken_image(device, position, err).await),
+                };
+
+                // Single frame GIFs are shown like any other image
+                if frames.len() > 1 {
+                    let frames = Arc::new(encode_frames(image_format, frames).await?);
+                    FRAME_CACHE.lock().await.insert(cache_key, frames.clone());
+
+                    Some(frames)
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(frames) = frames {
+            play_animation(&device.id, position, frames).await;
+
+            device.image_hashes.lock().await.insert(position, hash);
+            device
+                .images
+                .lock()
+                .await
+                .insert(position, ButtonImage::Encoded(bytes.into(), format));
+            metrics::increment(&metrics::IMAGES_SET);
+
+            return Ok(());
+        }
+    }
+
+    if can_pass_through(image_format, format, bytes) {
+        log::debug!("Image for button {} is already in device format", position);
+
+        device.write_image(key, bytes).await?;
+    } else {
+        let image = match load_from_memory_with_format(bytes, format) {
+            Ok(image) => image,
+            Err(err) => return Err(show_broken_image(device, position, err).await),
+        };
+
+        device
+            .set_button_image(key, image_format, image, CONFIG.jpeg_quality)
+            .await?;
+    }
+
+    device.image_hashes.lock().await.insert(position, hash);
+    device
+        .images
+        .lock()
+        .await
+        .insert(position, ButtonImage::Encoded(bytes.into(), format));
+
+    flush_debounced(device).await?;
+    metrics::increment(&metrics::IMAGES_SET);
+
+    Ok(())
+}
+
+/// Sets image of the button at OpenDeck position from an image file and flushes it, the format
+/// is taken from the file extension, or guessed from the data if the extension isn't known
+pub async fn set_button_image_file<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    path: &Path,
+) -> Result<(), DeviceError> {
+    let result = async {
+        let bytes = tokio::fs::read(path)
+            .await
+            .map_err(|err| DeviceError::File(path.to_path_buf(), err))?;
+
+        let format = match image::ImageFormat::from_path(path) {
+            Ok(format) => format,
+            Err(_) => image::guess_format(&bytes)?,
+        };
+
+        set_button_image_bytes(device, position, &bytes, format).await
+    }
+    .await;
+
+    if let Err(err) = &result {
+        log::error!(
+            "Failed to set image of button {} from {}: {}",
+            position,
+            path.display(),
+            err
+        );
+    }
+
+    result
+}
+
+/// Replaces image of the button at OpenDeck position with [broken_image] if the config asks
+/// for it, so an image that failed to decode doesn't leave the previous one in its place
+///
+/// Returns the decode error, or the error of the device if showing the placeholder failed
+async fn show_broken_image<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    err: ImageError,
+) -> DeviceError {
+    show_broken_image_with(device, position, err, &CONFIG).await
+}
+
+/// Same as [show_broken_image], with the given config
+async fn show_broken_image_with<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    err: ImageError,
+    config: &Config,
+) -> DeviceError {
+    if !config.broken_image_placeholder {
+        return err.into();
+    }
+
+    log::debug!("Showing placeholder on {} of {}", position, device.id);
+
+    let image_format = get_image_format_for_key(&device.kind, position);
+    let (width, height) = image_format.size;
+    let placeholder = broken_image(width as u32, height as u32);
+
+    if let Err(err) = device
+        .set_button_image(
+            opendeck_to_device(device.kind.layout(), position),
+            image_format,
+            placeholder,
+            config.jpeg_quality,
+        )
+        .await
+    {
+        return err.into();
+    }
+
+    device.image_hashes.lock().await.remove(&position);
+    device.images.lock().await.remove(&position);
+
+    if let Err(err) = flush_debounced(device).await {
+        return err.into();
+    }
+
+    err.into()
+}
+
+/// Writes an image rendered by the plugin to the button at OpenDeck position in place of
+/// whatever it showed, without flushing it, the caller holds the lock of the position
+async fn write_rendered_image<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    image: DynamicImage,
+) -> Result<(), DeviceError> {
+    stop_animation(&device.id, position).await;
+
+    device
+        .set_button_image(
+            opendeck_to_device(device.kind.layout(), position),
+            get_image_format_for_key(&device.kind, position),
+            image.clone(),
+            CONFIG.jpeg_quality,
+        )
+        .await?;
+
+    device.image_hashes.lock().await.remove(&position);
+    device
+        .images
+        .lock()
+        .await
+        .insert(position, ButtonImage::Decoded(image));
+    metrics::increment(&metrics::IMAGES_SET);
+
+    Ok(())
+}
+
+/// Fills the button at OpenDeck position with a solid color and flushes it, a cheap way to
+/// show a status without going through image decoding
+pub async fn set_button_color<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    rgb: [u8; 3],
+) -> Result<(), DeviceError> {
+    check_position(device.kind.layout(), position)?;
+
+    let _guard = device.lock_position(position).await;
+
+    let (width, height) = get_image_format_for_key(&device.kind, position).size;
+    let image = RgbImage::from_pixel(width as u32, height as u32, Rgb(rgb));
+    let image = DynamicImage::ImageRgb8(image);
+
+    write_rendered_image(device, position, image).await?;
+    flush_debounced(device).await?;
+
+    Ok(())
+}
+
+/// Renders text centered on the button at OpenDeck position with the font size and color
+/// from the config and flushes it, for status displays without pre-rendered icons
+#[cfg(feature = "text")]
+pub async fn set_button_text<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    text: &str,
+) -> Result<(), DeviceError> {
+    check_position(device.kind.layout(), position)?;
+
+    let _guard = device.lock_position(position).await;
+
+    let (width, height) = get_image_format_for_key(&device.kind, position).size;
+    let image = crate::images::render_text(
+        text,
+        width as u32,
+        height as u32,
+        CONFIG.text_font_size,
+        CONFIG.text_color,
+    );
+
+    write_rendered_image(device, position, image).await?;
+    flush_debounced(device).await?;
+
+    Ok(())
+}
+
+/// Draws an image over a part of the button at OpenDeck position, e.g. an indicator dot,
+/// at `x` and `y` pixels from the top left corner of the image as it's shown
+///
+/// The device only takes whole images, so the region is composed onto the last image set on
+/// the button, or a black one if there is none, and the result is sent, an animation stops
+/// at its first frame
+pub async fn set_button_region<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    position: u8,
+    x: u32,
+    y: u32,
+    region: &DynamicImage,
+) -> Result<(), DeviceError> {
+    check_position(device.kind.layout(), position)?;
+
+    let _guard = device.lock_position(position).await;
+
+    let (width, height) = get_image_format_for_key(&device.kind, position).size;
+
+    let base = device.images.lock().await.get(&position).cloned();
+    let mut image = match base {
+        Some(base) => fit_to_size(base.decode()?, width as u32, height as u32),
+        None => DynamicImage::new_rgb8(width as u32, height as u32),
+    };
+    overlay(&mut image, region, x as i64, y as i64);
+
+    write_rendered_image(device, position, image).await?;
+    flush_debounced(device).await?;
+
+    Ok(())
+}
+
+/// Sets images of several buttons at OpenDeck positions with a single flush at the end, so they
+/// all appear at once, nothing is sent if any of the positions is outside the keypad
+pub async fn set_images<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    items: &[(u8, DynamicImage)],
+) -> Result<(), DeviceError> {
+    let layout = device.kind.layout();
+    for (position, _) in items {
+        check_position(layout, *position)?;
+    }
+
+    for (position, image) in items {
+        let _guard = device.lock_position(*position).await;
+
+        write_rendered_image(device, *position, image.clone()).await?;
+    }
+
+    flush_with_retries(device).await?;
+
+    Ok(())
+}
+
+/// Sets the same image on every button with a single flush at the end, e.g. for a uniform
+/// background across the whole deck
+pub async fn fill_all_buttons<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    image: DynamicImage,
+) -> Result<(), DeviceError> {
+    let items = (0..device.kind.layout().key_count() as u8)
+        .map(|position| (position, image.clone()))
+        .collect::<Vec<_>>();
+
+    set_images(device, &items).await
+}
+
+/// Clears buttons at OpenDeck positions with a single flush at the end, positions outside
+/// the keypad are skipped
+pub async fn clear_buttons<D: DeviceIo>(
+    device: &DeviceHandle<D>,
+    positions: &[u8],
+) -> Result<(), DeviceError> {
+    let mut cleared = false;
+
+    for &position in positions {
+        if let Err(err) = check_position(device.kind.layout(), position) {
+            log::error!("Skipping clear: {}", err);
+            continue;
+        }
+
+        let _guard = device.lock_position(position).await;
+
+        stop_animation(&device.id, position).await;
+
+        device
+            .clear_button_image(opendeck_to_device(device.kind.layout(), position))
+            .await?;
+        device.image_hashes.lock().await.remove(&position);
+        device.images.lock().await.remove(&position);
+        metrics::increment(&metrics::IMAGES_CLEARED);
+
+        cleared = true;
+    }
+
+    if cleared {
+        flush_debounced(device).await?;
+    }
+
+    Ok(())
+}
+
+/// Number of attempts to flush image updates before the error is passed on, a single failed
+/// flush shouldn't drop the connection in the middle of a profile switch
+pub const FLUSH_ATTEMPTS: u32 = 3;
+/// Delay between attempts to flush image updates
+pub const FLUSH_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Flushes the device, retrying up to [FLUSH_ATTEMPTS] times
+async fn flush_with_retries<D: DeviceIo>(device: &D) -> Result<(), MirajazzError> {
+    retry("flush", FLUSH_ATTEMPTS, FLUSH_RETRY_DELAY, || {
+        device.flush()
+    })
+    .await
+}
+
+/// Flushes the device right away, or with `flush_debounce_ms` set, schedules a single flush
+/// for all the image updates arriving within the window, so profile switches don't tear
+///
+/// The scheduled flush goes to the backend of the given handle, so it also reaches handles
+/// that aren't registered
+pub async fn flush_debounced<D: DeviceIo>(device: &DeviceHandle<D>) -> Result<(), MirajazzError> {
+    let window = device.flush_window;
+
+    if window.is_zero() {
+        return flush_with_retries(device).await;
+    }
+
+    // Flush is already scheduled, it will pick this update up as well
+    if !PENDING_FLUSHES.lock().await.insert(device.id.clone()) {
+        return Ok(());
+    }
+
+    let id = device.id.clone();
+    let backend = device.backend.clone();
+
+    TRACKER.lock().await.spawn(async move {
+        tokio::time::sleep(window).await;
+
+        // Updates arriving from now on schedule another flush
+        PENDING_FLUSHES.lock().await.remove(&id);
+
+        let Err(err) = flush_with_retries(backend.as_ref()).await else {
+            return;
+        };
+
+        // A failed flush of a connection that was since replaced says nothing about the
+        // current one, and a handle that was never registered has no connection to drop
+        let registered = get_device(&id).await.is_some_and(|device| {
+            Arc::as_ptr(&device.backend).cast::<()>() == Arc::as_ptr(&backend).cast::<()>()
+        });
+
+        if registered {
+            handle_error(&id, err.into()).await;
+        } else {
+            log::warn!("Failed to flush {}: {}", id, err);
+        }
+    });
+
+    Ok(())
+}
+
+/// Shortest delay between animation frames, GIFs often leave the delay at zero and
+/// expect it to be treated like browsers do
+pub const ANIMATION_MIN_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// Image OpenDeck sent for a button playing an animation, waiting for the animation to end
+pub type QueuedImage = (Arc<[u8]>, image::ImageFormat);
+
+/// Keeps an image OpenDeck sent for the button at OpenDeck position from interrupting the
+/// animation playing on it, if `animation_updates` asks for it
+///
+/// Returns true if the image is queued or dropped rather than shown now
+async fn hold_back_for_animation(
+    id: &str,
+    position: u8,
+    bytes: &[u8],
+    format: image::ImageFormat,
+    updates: AnimationUpdates,
+) -> bool {
+    let key = (id.to_string(), position);
+
+    if updates == AnimationUpdates::Replace || !ANIMATIONS.lock().await.contains_key(&key) {
+        return false;
+    }
+
+    if updates == AnimationUpdates::Queue {
+        log::debug!(
+            "Queueing image for button {} until its animation ends",
+            position
+        );
+
+        QUEUED_IMAGES
+            .lock()
+            .await
+            .insert(key, (bytes.into(), format));
+    } else {
+        log::debug!(
+            "Ignoring image for button {} playing an animation",
+            position
+        );
+    }
+
+    true
+}
+
+/// Returns key of the frames of a GIF encoded for the image format in [FRAME_CACHE]
+fn frame_cache_key(gif_hash: u64, image_format: ImageFormat) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    gif_hash.hash(&mut hasher);
+    image_format.hash(&mut hasher);
+    CONFIG.jpeg_quality.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Encodes the frames of an animation into the data the device expects
+async fn encode_frames(
+    image_format: ImageFormat,
+    frames: Vec<(DynamicImage, Duration)>,
+) -> Result<EncodedFrames, MirajazzError> {
+    let mut encoded = Vec::with_capacity(frames.len());
+
+    for (image, delay) in frames {
+        let image_data = encode_image(image_format, image, CONFIG.jpeg_quality).await?;
+
+        encoded.push((image_data, delay.max(ANIMATION_MIN_FRAME_DELAY)));
+    }
+
+    Ok(encoded)
+}
+
+/// Encodes the frames for the button at OpenDeck position and starts playing them in a loop,
+/// until [stop_animation] is called for the position or an image queued for it ends it
+pub async fn start_animation(
+    id: &str,
+    position: u8,
+    image_format: ImageFormat,
+    frames: Vec<(DynamicImage, Duration)>,
+) -> Result<(), MirajazzError> {
+    let encoded = encode_frames(image_format, frames).await?;
+
+    play_animation(id, position, Arc::new(encoded)).await;
+
+    Ok(())
+}
+
+/// Starts playing encoded frames on the button at OpenDeck position, see [start_animation]
+async fn play_animation(id: &str, position: u8, encoded: Arc<EncodedFrames>) {
+    log::debug!(
+        "Starting animation of {} frames for button {}",
+        encoded.len(),
+        position
+    );
+
+    let token = CancellationToken::new();
+    ANIMATIONS
+        .lock()
+        .await
+        .insert((id.to_string(), position), token.clone());
+
+    TRACKER
+        .lock()
+        .await
+        .spawn(animation_task(id.to_string(), position, encoded, token));
+}
+
+/// Stops the animation playing on the button at OpenDeck position, if any
+///
+/// An image queued for the animation is dropped, whatever stopped it came after the image
+pub async fn stop_animation(id: &str, position: u8) {
+    let key = (id.to_string(), position);
+
+    if let Some(token) = ANIMATIONS.lock().await.remove(&key) {
+        log::debug!("Stopping animation for button {}", position);
+
+        token.cancel();
+    }
+
+    QUEUED_IMAGES.lock().await.remove(&key);
+}
+
+/// Stops all the animations playing on the device
+pub async fn stop_animations(id: &str) {
+    ANIMATIONS.lock().await.retain(|(device_id, _), token| {
+        if device_id != id {
+            return true;
+        }
+
+        token.cancel();
+
+        false
+    });
+
+    QUEUED_IMAGES
+        .lock()
+        .await
+        .retain(|(device_id, _), _| device_id != id);
+}
+
+/// Writes and flushes encoded frames one after another, starting over after the last one
+async fn animation_task(
+    id: String,
+    position: u8,
+    frames: Arc<EncodedFrames>,
+    token: CancellationToken,
+) {
+    loop {
+        for (image_data, delay) in frames.iter() {
+            let Some(device) = get_device(&id).await else {
+                return;
+            };
+            let key = opendeck_to_device(device.kind.layout(), position);
+
+            // Images replacing the animation stop it while holding the lock, so checking
+            // after locking keeps a late frame from covering them
+            let guard = device.lock_position(position).await;
+
+            if token.is_cancelled() {
+                return;
+            }
+
+            let result = match device.write_image(key, image_data).await {
+                Ok(()) => flush_with_retries(device.as_ref()).await,
+                Err(err) => Err(err),
+            };
+
+            drop(guard);
+            drop(device);
+
+            if let Err(err) = result {
+                handle_error(&id, err.into()).await;
+
+                return;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(*delay) => {},
+                _ = token.cancelled() => return,
+            }
+        }
+
+        let queued = QUEUED_IMAGES.lock().await.remove(&(id.clone(), position));
+
+        if let Some((bytes, format)) = queued {
+            let Some(device) = get_device(&id).await else {
+                return;
+            };
+
+            log::debug!(
+                "Animation of button {} ended, showing queued image",
+                position
+            );
+
+            // Stops this animation before the image is written
+            if let Err(err) = show_queued_image(device, position, bytes, format).await {
+                handle_error(&id, err).await;
+            }
+
+            return;
+        }
+    }
+}
+
+/// Sets an image queued by [hold_back_for_animation], boxed as it may start another
+/// [animation_task] from within one, which the compiler can't tell is [Send] otherwise
+fn show_queued_image(
+    device: Arc<DeviceHandle>,
+    position: u8,
+    bytes: Arc<[u8]>,
+    format: image::ImageFormat,
+) -> futures_lite::future::Boxed<Result<(), DeviceError>> {
+    async move { set_button_image_bytes(device.as_ref(), position, &bytes, format).await }.boxed()
+}
+
+/// Environment variable that runs [run_self_test] on every device once it's registered,
+/// when set to `1` or `true`
+pub const SELF_TEST_ENV: &str = "OPENDECK_AMPGD6_SELF_TEST";
+
+/// Time each button is lit for before the next one during [run_self_test]
+pub const SELF_TEST_STEP: Duration = Duration::from_millis(300);
+
+fn self_test_enabled() -> bool {
+    std::env::var(SELF_TEST_ENV).is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// Lights every button in OpenDeck order with a color of its own, then clears them all and
+/// asks OpenDeck for the images again, so users can check that every screen works and the key map puts buttons where they belong
+pub async fn run_self_test(id: &str) -> Result<(), DeviceError> {
+    run_self_test_with(id, SELF_TEST_STEP).await
+}
+
+/// Same as [run_self_test], lighting each button for the given time
+async fn run_self_test_with(id: &str, step: Duration) -> Result<(), DeviceError> {
+    let layout = match get_device(id).await {
+        Some(device) => device.kind.layout(),
+        None => return Ok(()),
+    };
+
+    log::info!("Running self-test on {}", id);
+
+    for position in 0..layout.key_count() as u8 {
+        match get_device(id).await {
+            Some(device) => {
+                set_button_color(&device, position, self_test_color(layout, position)).await?
+            }
+            None => return Ok(()),
+        }
+
+        tokio::time::sleep(step).await;
+    }
+
+    let positions: Vec<u8> = (0..layout.key_count() as u8).collect();
+
+    if let Some(device) = get_device(id).await {
+        clear_buttons(&device, &positions).await?;
+    }
+
+    log::info!("Self-test on {} finished", id);
+
+    // Bring back the images the test painted over
+    request_refresh(id).await;
+
+    Ok(())
+}
+
+/// Environment variable that shows [set_debug_overlay] on every device once it's registered,
+/// when set to `1` or `true`
+#[cfg(feature = "text")]
+pub const DEBUG_OVERLAY_ENV: &str = "OPENDECK_AMPGD6_DEBUG_OVERLAY";
+
+/// Time between refreshes of the debug overlay, image updates per second are averaged over it
+#[cfg(feature = "text")]
+pub const DEBUG_OVERLAY_REFRESH: Duration = Duration::from_secs(1);
+
+#[cfg(feature = "text")]
+fn debug_overlay_enabled() -> bool {
+    std::env::var(DEBUG_OVERLAY_ENV).is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// Returns OpenDeck position the debug overlay is shown on, the bottom right corner
+#[cfg(feature = "text")]
+pub fn debug_overlay_position(layout: Layout) -> u8 {
+    layout.key_count() as u8 - 1
+}
+
+/// Shows or hides image updates per second and the number of errors across all devices on
+/// the corner button of the device, see [debug_overlay_position]
+///
+/// The overlay is drawn over whatever the button shows without replacing it, so once the
+/// overlay is hidden, the button shows its own image again
+#[cfg(feature = "text")]
+pub async fn set_debug_overlay(id: &str, enabled: bool) {
+    let mut overlays = DEBUG_OVERLAYS.lock().await;
+
+    if enabled {
+        if overlays.contains_key(id) {
+            return;
+        }
+
+        log::info!("Showing debug overlay on {}", id);
+
+        let token = CancellationToken::new();
+        overlays.insert(id.to_string(), token.clone());
+        drop(overlays);
+
+        TRACKER
+            .lock()
+            .await
+            .spawn(debug_overlay_task(id.to_string(), token));
+    } else if let Some(token) = overlays.remove(id) {
+        drop(overlays);
+
+        log::info!("Hiding debug overlay on {}", id);
+        token.cancel();
+
+        if let Err(err) = hide_debug_overlay(id).await {
+            handle_error(id, err).await;
+        }
+    }
+}
+
+/// Draws the debug overlay every [DEBUG_OVERLAY_REFRESH] until it's hidden or the device is gone
+#[cfg(feature = "text")]
+async fn debug_overlay_task(id: String, token: CancellationToken) {
+    let mut last_images = metrics::IMAGES_SET.load(std::sync::atomic::Ordering::Relaxed);
+    let mut last_refresh = Instant::now();
+
+    loop {
+        let Some(device) = get_device(&id).await else {
+            break;
+        };
+
+        let images = metrics::IMAGES_SET.load(std::sync::atomic::Ordering::Relaxed);
+        let elapsed = last_refresh.elapsed().as_secs_f32().max(f32::EPSILON);
+        let fps = images.saturating_sub(last_images) as f32 / elapsed;
+        let errors = metrics::ERRORS.load(std::sync::atomic::Ordering::Relaxed);
+        (last_images, last_refresh) = (images, Instant::now());
+
+        let position = debug_overlay_position(device.kind.layout());
+        let image_format = get_image_format_for_key(&device.kind, position);
+        let (width, height) = image_format.size;
+        let text = format!("{:.1} fps\n{} err", fps, errors);
+        let overlay = crate::images::render_text(
+            &text,
+            width as u32,
+            height as u32,
+            CONFIG.text_font_size,
+            CONFIG.text_color,
+        );
+
+        // Taken after rendering, so a late refresh can't cover the image put back on hiding
+        let guard = device.lock_position(position).await;
+        if token.is_cancelled() {
+            return;
+        }
+
+        // Written without recording it, the button keeps its own image for hiding
+        let result = match device
+            .set_button_image(
+                opendeck_to_device(device.kind.layout(), position),
+                image_format,
+                overlay,
+                CONFIG.jpeg_quality,
+            )
+            .await
+        {
+            Ok(()) => flush_with_retries(device.as_ref()).await,
+            Err(err) => Err(err),
+        };
+
+        drop(guard);
+        drop(device);
+
+        if let Err(err) = result
+            && !handle_error(&id, err.into()).await
+        {
+            break;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(DEBUG_OVERLAY_REFRESH) => {},
+            _ = token.cancelled() => return,
+        }
+    }
+
+    DEBUG_OVERLAYS.lock().await.remove(&id);
+}
+
+/// Puts back the image the debug overlay covered, or clears the button if it had none
+#[cfg(feature = "text")]
+async fn hide_debug_overlay(id: &str) -> Result<(), DeviceError> {
+    let Some(device) = get_device(id).await else {
+        return Ok(());
+    };
+
+    let position = debug_overlay_position(device.kind.layout());
+    let image = device.images.lock().await.get(&position).cloned();
+
+    match image {
+        Some(image) => restore_image(&device, position, image).await?,
+        None => {
+            let _guard = device.lock_position(position).await;
+
+            device
+                .clear_button_image(opendeck_to_device(device.kind.layout(), position))
+                .await?
+        }
+    }
+
+    flush_with_retries(device.as_ref()).await?;
+
+    Ok(())
+}
+
+/// Returns color of the button at OpenDeck position in [run_self_test], buttons go around
+/// the hue circle so neighbours are easy to tell apart
+fn self_test_color(layout: Layout, position: u8) -> [u8; 3] {
+    let hue = position as f32 / layout.key_count() as f32 * 6.0;
+    let rising = (255.0 * (1.0 - (hue % 2.0 - 1.0).abs())) as u8;
+
+    match hue as u8 {
+        0 => [255, rising, 0],
+        1 => [rising, 255, 0],
+        2 => [0, 255, rising],
+        3 => [0, rising, 255],
+        4 => [rising, 0, 255],
+        _ => [255, 0, rising],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        mappings::{
+            AMPGD6_PID, AMPGD6V2_PID, ENCODER_CCW_INPUT, ENCODER_CW_INPUT, ENCODER_PRESS_INPUT,
+            FIFINE_VID, KINDS,
+        },
+        simulated::DeviceCall,
+    };
+
+    /// Returns a handle of a scripted device together with the device, to script and inspect it
+    fn scripted_device(id: &str, protocol_version: usize) -> (DeviceHandle, SimulatedDevice) {
+        let device = SimulatedDevice::scripted(protocol_version);
+        let handle = DeviceHandle::new(
+            id.to_string(),
+            DeviceBackend::Simulated(device.clone()),
+            Kind::AMPGD6,
+        );
+
+        (handle, device)
+    }
+
+    /// Returns a PNG filled with the color, encoded as a data url like OpenDeck sends it
+    fn png_data_url(color: [u8; 3]) -> String {
+        data_url(color, image::ImageFormat::Png)
+    }
+
+    /// Same as [png_data_url], in any format the image crate can write
+    fn data_url(color: [u8; 3], format: image::ImageFormat) -> String {
+        let mut bytes = vec![];
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb(color)))
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+
+        let body: String = bytes.iter().map(|b| format!("%{:02X}", b)).collect();
+
+        format!("data:{},{}", format.to_mime_type(), body)
+    }
+
+    /// Returns the image written to the key, decoded from the JPEG sent to the device
+    fn written_image(device: &SimulatedDevice, key: u8) -> DynamicImage {
+        let data = device
+            .calls()
+            .into_iter()
+            .find_map(|call| match call {
+                DeviceCall::WriteImage(k, data) if k == key => Some(data),
+                _ => None,
+            })
+            .expect("Nothing was written to the key");
+
+        image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).unwrap()
+    }
+
+    /// Returns a two frame GIF, encoded as a data url like OpenDeck sends it
+    fn gif_data_url() -> String {
+        let mut bytes = vec![];
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut bytes);
+            for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+                let frame = image::Frame::from_parts(
+                    image::RgbaImage::from_pixel(8, 8, image::Rgba(color)),
+                    0,
+                    0,
+                    image::Delay::from_numer_denom_ms(100, 1),
+                );
+                encoder.encode_frame(frame).unwrap();
+            }
+        }
+
+        let body: String = bytes.iter().map(|b| format!("%{:02X}", b)).collect();
+
+        format!("data:image/gif,{}", body)
+    }
+
+    /// Returns true if an animation plays on the button of the device
+    async fn animating(id: &str, position: u8) -> bool {
+        ANIMATIONS
+            .lock()
+            .await
+            .contains_key(&(id.to_string(), position))
+    }
+
+    fn set_image_event(id: &str, position: Option<u8>, image: Option<String>) -> SetImageEvent {
+        SetImageEvent {
+            device: id.to_string(),
+            controller: None,
+            position,
+            image,
+        }
+    }
+
+    /// Subscription to the updates devices forward, see [subscribe_events]
+    type Events = broadcast::Receiver<(String, DeviceStateUpdate)>;
+
+    /// Config for running [device_events_task], without heartbeats and bounce filtering
+    fn events_config(dedup_enabled: bool) -> &'static Config {
+        Box::leak(Box::new(Config {
+            dedup_enabled,
+            watchdog_interval_ms: 0,
+            bounce_window_ms: 0,
+            ..Config::default()
+        }))
+    }
+
+    /// Registers a scripted device and starts reading its events, returning the device to
+    /// feed input to and a subscription to the updates it forwards, see [next_events]
+    async fn start_events(
+        id: &str,
+        protocol_version: usize,
+        config: &'static Config,
+    ) -> (SimulatedDevice, Events) {
+        start_events_with(id, protocol_version, config, vec![]).await
+    }
+
+    /// Same as [start_events], with updates the device reports right after connecting
+    async fn start_events_with(
+        id: &str,
+        protocol_version: usize,
+        config: &'static Config,
+        at_connect: Vec<Vec<DeviceStateUpdate>>,
+    ) -> (SimulatedDevice, Events) {
+        let (handle, device) = scripted_device(id, protocol_version);
+        for updates in at_connect {
+            device.feed_updates(updates);
+        }
+        DEVICES
+            .write()
+            .await
+            .insert(id.to_string(), Arc::new(handle));
+
+        let events = subscribe_events();
+        let task_id = id.to_string();
+        tokio::spawn(async move { device_events_task(&task_id, config).await });
+
+        // Input fed before the reader settles counts as held since connecting
+        tokio::time::sleep(CONNECT_SETTLE_TIMEOUT * 2).await;
+
+        (device, events)
+    }
+
+    /// Waits for the next updates the device forwards, as their debug output for comparing
+    async fn next_events(events: &mut Events, id: &str, count: usize) -> Vec<String> {
+        let mut updates = vec![];
+
+        while updates.len() < count {
+            let (event_id, update) =
+                tokio::time::timeout(Duration::from_secs(5), recv_event(events))
+                    .await
+                    .expect("Timed out waiting for updates")
+                    .unwrap();
+
+            if event_id == id {
+                updates.push(format!("{:?}", update));
+            }
+        }
+
+        updates
+    }
+
+    /// Returns true if the device forwards nothing more within a second
+    async fn no_more_events(events: &mut Events, id: &str) -> bool {
+        tokio::time::timeout(Duration::from_secs(1), next_events(events, id, 1))
+            .await
+            .is_err()
+    }
+
+    #[test]
+    fn device_of_other_kind_is_refused() {
+        assert!(reports_kind(&Kind::AMPGD6, FIFINE_VID, AMPGD6_PID));
+        assert!(reports_kind(&Kind::AMPGD6V2, FIFINE_VID, AMPGD6V2_PID));
+
+        assert!(!reports_kind(&Kind::AMPGD6, FIFINE_VID, AMPGD6V2_PID));
+        assert!(!reports_kind(&Kind::AMPGD6V2, FIFINE_VID, AMPGD6_PID));
+        assert!(!reports_kind(&Kind::AMPGD6, 0x1234, AMPGD6_PID));
+    }
+
+    #[test]
+    fn forced_protocol_version_takes_precedence() {
+        for kind in KINDS {
+            let default = protocol_version("test-protocol", &kind, &Config::default());
+            assert_eq!(default, kind.protocol_version());
+
+            for version in 1..=3 {
+                let config = Config {
+                    force_protocol_version: Some(version),
+                    ..Config::default()
+                };
+
+                assert_eq!(protocol_version("test-protocol", &kind, &config), version);
+            }
+        }
+
+        // Versions mirajazz doesn't know are dropped when the config is loaded
+        let mut config = Config {
+            force_protocol_version: Some(4),
+            ..Config::default()
+        };
+        config.validate();
+        assert_eq!(protocol_version("test-protocol", &Kind::AMPGD6, &config), 1);
+    }
+
+    #[tokio::test]
+    async fn set_image_writes_mapped_key_and_flushes() {
+        let id = "test-set-image";
+        let (handle, device) = scripted_device(id, 1);
+
+        handle_set_image(
+            &handle,
+            set_image_event(id, Some(0), Some(png_data_url([255, 0, 0]))),
+        )
+        .await
+        .unwrap();
+
+        let calls = device.take_calls();
+        assert!(matches!(
+            calls.as_slice(),
+            [DeviceCall::WriteImage(10, _), DeviceCall::Flush]
+        ));
+    }
+
+    #[tokio::test]
+    async fn png_and_bmp_images_are_decoded() {
+        let (width, height) = get_image_format_for_key(&Kind::AMPGD6, 0).size;
+
+        for format in [image::ImageFormat::Png, image::ImageFormat::Bmp] {
+            let id = "test-decode-formats";
+            let (handle, device) = scripted_device(id, 1);
+
+            handle_set_image(
+                &handle,
+                set_image_event(id, Some(0), Some(data_url([255, 0, 0], format))),
+            )
+            .await
+            .unwrap();
+
+            let image = written_image(&device, 10);
+            assert_eq!(
+                (image.width(), image.height()),
+                (width as u32, height as u32)
+            );
+            assert!(image.to_rgb8().get_pixel(0, 0)[0] > 200);
+        }
+    }
+
+    #[cfg(feature = "webp")]
+    #[tokio::test]
+    async fn webp_image_is_decoded() {
+        let id = "test-decode-webp";
+        let (handle, device) = scripted_device(id, 1);
+
+        handle_set_image(
+            &handle,
+            set_image_event(
+                id,
+                Some(0),
+                Some(data_url([0, 0, 255], image::ImageFormat::WebP)),
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert!(written_image(&device, 10).to_rgb8().get_pixel(0, 0)[2] > 200);
+    }
+
+    #[tokio::test]
+    async fn malformed_data_urls_are_not_fatal() {
+        let id = "test-malformed-data-url";
+        let (handle, device) = scripted_device(id, 1);
+        let event = |image: &str| set_image_event(id, Some(0), Some(image.to_string()));
+
+        let err = handle_set_image(&handle, event("not a data url"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeviceError::DataUrl(_)));
+        assert!(!err.is_fatal());
+
+        // Cut off in the middle of a base64 quantum
+        let err = handle_set_image(&handle, event("data:image/png;base64,iVBORw0KGgoAA"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeviceError::Base64(_)));
+        assert!(!err.is_fatal());
+
+        // Valid base64 of a cut off PNG
+        let err = handle_set_image(&handle, event("data:image/png;base64,iVBORw0KGgo="))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DeviceError::Image(_)));
+        assert!(!err.is_fatal());
+
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn images_use_kind_resolved_at_connect() {
+        let id = "test-kind-resolved";
+        let device = SimulatedDevice::scripted(2);
+        // Kind is only ever looked up from VID and PID before the handle is created
+        let handle = DeviceHandle::new(
+            id.to_string(),
+            DeviceBackend::Simulated(device.clone()),
+            Kind::from_vid_pid(FIFINE_VID, AMPGD6V2_PID).unwrap(),
+        );
+
+        for position in [0, 14] {
+            handle_set_image(
+                &handle,
+                set_image_event(id, Some(position), Some(png_data_url([0, 0, 255]))),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(handle.kind, Kind::AMPGD6V2);
+        let (width, height) = Kind::AMPGD6V2.key_image_size(0);
+        for key in [10, 4] {
+            let image = written_image(&device, key);
+            assert_eq!(
+                (image.width(), image.height()),
+                (width as u32, height as u32)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_jpeg_bytes_are_set() {
+        let id = "test-raw-bytes";
+        let (handle, device) = scripted_device(id, 1);
+        let mut jpeg = vec![];
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([0, 255, 0])))
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+
+        set_button_image_bytes(&handle, 5, &jpeg, image::ImageFormat::Jpeg)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            device.calls().as_slice(),
+            [DeviceCall::WriteImage(5, _), DeviceCall::Flush]
+        ));
+        assert!(written_image(&device, 5).to_rgb8().get_pixel(0, 0)[1] > 200);
+        assert!(matches!(
+            handle.images.lock().await.get(&5),
+            Some(ButtonImage::Encoded(_, image::ImageFormat::Jpeg))
+        ));
+    }
+
+    #[tokio::test]
+    async fn jpeg_file_is_set() {
+        let id = "test-image-file";
+        let (handle, device) = scripted_device(id, 1);
+        let path = std::env::temp_dir().join(format!("opendeck-ampgd6-{}.jpg", std::process::id()));
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([0, 255, 0])))
+            .save_with_format(&path, image::ImageFormat::Jpeg)
+            .unwrap();
+
+        let result = set_button_image_file(&handle, 5, &path).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert!(matches!(
+            device.calls().as_slice(),
+            [DeviceCall::WriteImage(5, _), DeviceCall::Flush]
+        ));
+        assert!(written_image(&device, 5).to_rgb8().get_pixel(0, 0)[1] > 200);
+    }
+
+    #[tokio::test]
+    async fn missing_image_file_is_an_error() {
+        let id = "test-missing-image-file";
+        let (handle, device) = scripted_device(id, 1);
+        let path = std::env::temp_dir().join("opendeck-ampgd6-missing.jpg");
+
+        assert!(matches!(
+            set_button_image_file(&handle, 5, &path).await,
+            Err(DeviceError::File(..))
+        ));
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn color_fills_whole_button() {
+        let id = "test-button-color";
+        let (handle, device) = scripted_device(id, 1);
+
+        set_button_color(&handle, 0, [255, 128, 0]).await.unwrap();
+
+        let (width, height) = get_image_format_for_key(&Kind::AMPGD6, 0).size;
+        let image = match handle.images.lock().await.get(&0) {
+            Some(ButtonImage::Decoded(image)) => image.to_rgb8(),
+            _ => panic!("Color wasn't recorded as the image of the button"),
+        };
+        assert_eq!(image.dimensions(), (width as u32, height as u32));
+        assert!(image.pixels().all(|pixel| pixel.0 == [255, 128, 0]));
+
+        let written = written_image(&device, 10);
+        assert_eq!(
+            (written.width(), written.height()),
+            (width as u32, height as u32)
+        );
+        assert_eq!(device.calls().last(), Some(&DeviceCall::Flush));
+    }
+
+    #[tokio::test]
+    async fn same_image_is_written_once() {
+        let id = "test-same-image";
+        let (handle, device) = scripted_device(id, 1);
+        let image = png_data_url([0, 255, 0]);
+
+        for _ in 0..2 {
+            handle_set_image(&handle, set_image_event(id, Some(3), Some(image.clone())))
+                .await
+                .unwrap();
+        }
+
+        let writes = device
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, DeviceCall::WriteImage(..)))
+            .count();
+        assert_eq!(writes, 1);
+    }
+
+    #[tokio::test]
+    async fn refresh_lets_same_image_be_written_again() {
+        let id = "test-refresh-image";
+        let device = register_scripted(id).await;
+        let handle = get_device(id).await.unwrap();
+        let event = || set_image_event(id, Some(3), Some(png_data_url([0, 255, 0])));
+
+        handle_set_image(&handle, event()).await.unwrap();
+        // OpenDeck re-sends every image it's asked to re-render
+        request_refresh(id).await;
+        handle_set_image(&handle, event()).await.unwrap();
+
+        let writes = device
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, DeviceCall::WriteImage(..)))
+            .count();
+        assert_eq!(writes, 2);
+    }
+
+    #[tokio::test]
+    async fn restored_gif_is_written_again() {
+        let id = "test-restore-gif";
+        let (handle, device) = scripted_device(id, 1);
+        let gif = data_url([255, 0, 0], image::ImageFormat::Gif);
+
+        handle_set_image(&handle, set_image_event(id, Some(0), Some(gif)))
+            .await
+            .unwrap();
+        device.take_calls();
+
+        // Like hiding the debug overlay, which covered the button without recording it
+        let image = handle.images.lock().await.get(&0).cloned().unwrap();
+        restore_image(&handle, 0, image).await.unwrap();
+
+        assert!(
+            device
+                .calls()
+                .iter()
+                .any(|call| matches!(call, DeviceCall::WriteImage(10, _)))
+        );
+    }
+
+    #[tokio::test]
+    async fn every_state_change_is_drawn() {
+        let id = "test-button-states";
+        let (handle, device) = scripted_device(id, 1);
+        // OpenDeck sends the image of the state a button switched to as a regular image
+        let states = [[255, 0, 0], [0, 0, 255]];
+
+        for state in [0, 1, 0] {
+            let image = png_data_url(states[state]);
+            handle_set_image(&handle, set_image_event(id, Some(0), Some(image)))
+                .await
+                .unwrap();
+
+            let data = device
+                .take_calls()
+                .into_iter()
+                .find_map(|call| match call {
+                    DeviceCall::WriteImage(10, data) => Some(data),
+                    _ => None,
+                })
+                .expect("State change wasn't written");
+            let written = load_from_memory_with_format(&data, image::ImageFormat::Jpeg)
+                .unwrap()
+                .to_rgb8();
+            assert!(
+                written
+                    .get_pixel(52, 52)
+                    .0
+                    .iter()
+                    .zip(states[state])
+                    .all(|(a, b)| a.abs_diff(b) < 16)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn several_images_are_flushed_once() {
+        let id = "test-set-images";
+        let (handle, device) = scripted_device(id, 1);
+        let image = DynamicImage::new_rgb8(8, 8);
+        let items = [(0, image.clone()), (5, image.clone()), (14, image.clone())];
+
+        super::set_images(&handle, &items).await.unwrap();
+
+        let calls = device.take_calls();
+        let written: Vec<u8> = calls
+            .iter()
+            .filter_map(|call| match call {
+                DeviceCall::WriteImage(key, _) => Some(*key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(written, [10, 5, 4]);
+        assert_eq!(calls.iter().filter(|c| **c == DeviceCall::Flush).count(), 1);
+        assert_eq!(calls.last(), Some(&DeviceCall::Flush));
+
+        // One position outside the keypad and none of the images is sent
+        let items = [(0, image.clone()), (15, image)];
+        assert!(super::set_images(&handle, &items).await.is_err());
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn image_without_position_is_ignored() {
+        let id = "test-image-without-position";
+        let (handle, device) = scripted_device(id, 1);
+        set_button_color(&handle, 0, [255, 0, 0]).await.unwrap();
+        device.take_calls();
+
+        handle_set_image(
+            &handle,
+            set_image_event(id, None, Some(png_data_url([0, 0, 255]))),
+        )
+        .await
+        .unwrap();
+
+        assert!(device.calls().is_empty());
+        assert!(handle.images.lock().await.contains_key(&0));
+    }
+
+    #[tokio::test]
+    async fn corrupt_image_shows_placeholder_when_enabled() {
+        let id = "test-broken-image";
+        let (handle, device) = scripted_device(id, 1);
+        let corrupt = || {
+            load_from_memory_with_format(b"\xff\xd8\xff\xe0broken", image::ImageFormat::Jpeg)
+                .unwrap_err()
+        };
+
+        // Previous image stays by default
+        let err = show_broken_image_with(&handle, 0, corrupt(), &Config::default()).await;
+        assert!(matches!(err, DeviceError::Image(_)));
+        assert!(device.calls().is_empty());
+
+        let config = Config {
+            broken_image_placeholder: true,
+            ..Config::default()
+        };
+        let err = show_broken_image_with(&handle, 0, corrupt(), &config).await;
+        assert!(matches!(err, DeviceError::Image(_)));
+
+        let placeholder = written_image(&device, 10).to_rgb8();
+        let (width, height) = placeholder.dimensions();
+        let center = placeholder.get_pixel(width / 2, height / 2).0;
+        assert!(center[0] > 128 && center[1] < 64 && center[2] < 64);
+        assert!(placeholder.get_pixel(0, 0).0.iter().all(|c| *c < 32));
+        assert_eq!(device.calls().last(), Some(&DeviceCall::Flush));
+    }
+
+    #[tokio::test]
+    async fn fill_writes_every_button_and_flushes_once() {
+        let id = "test-fill-all";
+        let (handle, device) = scripted_device(id, 1);
+
+        fill_all_buttons(&handle, DynamicImage::new_rgb8(8, 8))
+            .await
+            .unwrap();
+
+        let calls = device.take_calls();
+        let mut written: Vec<u8> = calls
+            .iter()
+            .filter_map(|call| match call {
+                DeviceCall::WriteImage(key, _) => Some(*key),
+                _ => None,
+            })
+            .collect();
+        written.sort();
+        assert_eq!(written, (0..15).collect::<Vec<u8>>());
+        assert_eq!(calls.iter().filter(|c| **c == DeviceCall::Flush).count(), 1);
+        assert_eq!(handle.images.lock().await.len(), 15);
+    }
+
+    #[tokio::test]
+    async fn region_is_composed_onto_button_image() {
+        let id = "test-button-region";
+        let (handle, device) = scripted_device(id, 1);
+        set_button_color(&handle, 0, [0, 0, 255]).await.unwrap();
+        device.take_calls();
+
+        let dot = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, Rgb([255, 0, 0])));
+        set_button_region(&handle, 0, 20, 30, &dot).await.unwrap();
+
+        let image = match handle.images.lock().await.get(&0) {
+            Some(ButtonImage::Decoded(image)) => image.to_rgb8(),
+            _ => panic!("Composed image wasn't recorded as the image of the button"),
+        };
+        assert_eq!(image.get_pixel(25, 35).0, [255, 0, 0]);
+        assert_eq!(image.get_pixel(5, 5).0, [0, 0, 255]);
+        assert_eq!(image.get_pixel(35, 35).0, [0, 0, 255]);
+        assert_eq!(device.calls().last(), Some(&DeviceCall::Flush));
+
+        // Region past the keypad is refused
+        assert!(set_button_region(&handle, 15, 0, 0, &dot).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn later_image_for_a_button_wins() {
+        let id = "test-image-order";
+        let (handle, device) = scripted_device(id, 1);
+        let handle = Arc::new(handle);
+        let set = |color| {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let event = set_image_event(id, Some(0), Some(png_data_url(color)));
+                handle_set_image(&handle, event).await.unwrap();
+            })
+        };
+
+        // Both writers queue up behind a write in progress, in the order they asked
+        let busy = handle.lock_position(0).await;
+        let first = set([255, 0, 0]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let second = set([0, 0, 255]);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(busy);
+        first.await.unwrap();
+        second.await.unwrap();
+
+        let writes = device
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, DeviceCall::WriteImage(10, _)))
+            .count();
+        assert_eq!(writes, 2);
+        match handle.images.lock().await.get(&0) {
+            Some(image) => {
+                assert_eq!(
+                    image.decode().unwrap().to_rgb8().get_pixel(0, 0).0,
+                    [0, 0, 255]
+                )
+            }
+            None => panic!("Image wasn't recorded as the image of the button"),
+        }
+    }
+
+    #[tokio::test]
+    async fn position_outside_keypad_is_ignored() {
+        let id = "test-position-outside";
+        let (handle, device) = scripted_device(id, 1);
+
+        for image in [Some(png_data_url([0, 0, 255])), None] {
+            handle_set_image(&handle, set_image_event(id, Some(15), image))
+                .await
+                .unwrap();
+        }
+
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn clearing_position_clears_mapped_key() {
+        let id = "test-clear-position";
+        let (handle, device) = scripted_device(id, 1);
+
+        handle_set_image(&handle, set_image_event(id, Some(14), None))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            device.take_calls(),
+            vec![DeviceCall::ClearButtonImage(4), DeviceCall::Flush]
+        );
+    }
+
+    #[tokio::test]
+    async fn clearing_several_buttons_flushes_once() {
+        let id = "test-clear-buttons";
+        let (handle, device) = scripted_device(id, 1);
+
+        clear_buttons(&handle, &[0, 5, 15, 14]).await.unwrap();
+
+        assert_eq!(
+            device.take_calls(),
+            [
+                DeviceCall::ClearButtonImage(10),
+                DeviceCall::ClearButtonImage(5),
+                DeviceCall::ClearButtonImage(4),
+                DeviceCall::Flush
+            ]
+        );
+
+        // Nothing to flush if no position is on the keypad
+        clear_buttons(&handle, &[15]).await.unwrap();
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_write_is_fatal() {
+        let id = "test-failed-write";
+        let (handle, device) = scripted_device(id, 1);
+        device.fail_next(|call| matches!(call, DeviceCall::WriteImage(..)), 1);
+
+        let err = handle_set_image(
+            &handle,
+            set_image_event(id, Some(0), Some(png_data_url([0, 0, 255]))),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.is_fatal());
+        assert!(!device.calls().contains(&DeviceCall::Flush));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_is_retried() {
+        let device = SimulatedDevice::scripted(1);
+        device.fail_next(
+            |call| *call == DeviceCall::Flush,
+            FLUSH_ATTEMPTS as usize - 1,
+        );
+
+        flush_with_retries(&device).await.unwrap();
+
+        assert_eq!(device.calls().len(), FLUSH_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn image_survives_failed_flush() {
+        let id = "test-image-flush-retry";
+        let (handle, device) = scripted_device(id, 1);
+        device.fail_next(|call| *call == DeviceCall::Flush, 1);
+
+        handle_set_image(
+            &handle,
+            set_image_event(id, Some(0), Some(png_data_url([0, 255, 0]))),
+        )
+        .await
+        .unwrap();
+
+        let calls = device.calls();
+        assert!(matches!(calls[0], DeviceCall::WriteImage(10, _)));
+        assert_eq!(calls[1..], [DeviceCall::Flush, DeviceCall::Flush]);
+        assert!(handle.images.lock().await.contains_key(&0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn updates_within_window_are_flushed_once() {
+        let id = "test-flush-debounce";
+        let (mut handle, device) = scripted_device(id, 1);
+        handle.flush_window = Duration::from_millis(100);
+
+        for i in 0..15 {
+            handle_set_image(
+                &handle,
+                set_image_event(id, Some(0), Some(png_data_url([i * 10, 0, 0]))),
+            )
+            .await
+            .unwrap();
+        }
+        assert!(!device.calls().contains(&DeviceCall::Flush));
+
+        tokio::time::sleep(handle.flush_window * 2).await;
+
+        let calls = device.take_calls();
+        let flushes = calls.iter().filter(|call| **call == DeviceCall::Flush);
+        assert_eq!(flushes.count(), 1);
+        assert_eq!(calls.last(), Some(&DeviceCall::Flush));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn animation_flush_is_retried() {
+        let id = "test-animation-flush";
+        let device = register_scripted(id).await;
+        device.fail_next(
+            |call| *call == DeviceCall::Flush,
+            FLUSH_ATTEMPTS as usize - 1,
+        );
+
+        let frames = Arc::new(vec![
+            (vec![1], ANIMATION_MIN_FRAME_DELAY),
+            (vec![2], ANIMATION_MIN_FRAME_DELAY),
+        ]);
+        let token = CancellationToken::new();
+        let task = tokio::spawn(animation_task(id.to_string(), 0, frames, token.clone()));
+
+        tokio::time::sleep(ANIMATION_MIN_FRAME_DELAY * 3).await;
+        token.cancel();
+        task.await.unwrap();
+
+        assert!(get_device(id).await.is_some());
+        assert!(
+            device
+                .calls()
+                .contains(&DeviceCall::WriteImage(10, vec![2]))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connecting_is_retried_with_backoff() {
+        let token = CancellationToken::new();
+        let mut backoff = Backoff::default();
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let connect = || async {
+            match attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 | 1 => Err(MirajazzError::DeviceNotFoundError),
+                _ => Ok("device"),
+            }
+        };
+        let start = tokio::time::Instant::now();
+
+        let device = loop {
+            match connect().await {
+                Ok(device) => break device,
+                Err(_) => assert!(backoff.wait(&token).await),
+            }
+        };
+
+        assert_eq!(device, "device");
+        assert_eq!(attempts.into_inner(), 3);
+        assert_eq!(start.elapsed(), RECONNECT_INITIAL_BACKOFF * 3);
+        assert_eq!(backoff.0, RECONNECT_INITIAL_BACKOFF * 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_init_is_given_up_after_timeout() {
+        let token = CancellationToken::new();
+        let started = tokio::time::Instant::now();
+
+        let result = init_within(
+            std::future::pending::<()>(),
+            Duration::from_secs(10),
+            &token,
+        )
+        .await;
+
+        assert!(matches!(result, Some(Err(_))));
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        // Init finishing in time is passed on
+        let result = init_within(async { 1 }, Duration::from_secs(10), &token).await;
+        assert!(matches!(result, Some(Ok(1))));
+
+        token.cancel();
+        let result = init_within(
+            std::future::pending::<()>(),
+            Duration::from_secs(10),
+            &token,
+        )
+        .await;
+        assert!(result.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_is_capped_and_cancellable() {
+        let token = CancellationToken::new();
+        let mut backoff = Backoff::default();
+
+        for _ in 0..10 {
+            assert!(backoff.wait(&token).await);
+        }
+        assert_eq!(backoff.0, RECONNECT_MAX_BACKOFF);
+
+        token.cancel();
+        assert!(!backoff.wait(&token).await);
+
+        backoff.reset();
+        assert_eq!(backoff.0, RECONNECT_INITIAL_BACKOFF);
+    }
+
+    /// Returns brightness values set on the device since the last call
+    fn brightness_calls(device: &SimulatedDevice) -> Vec<u8> {
+        device
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                DeviceCall::SetBrightness(value) => Some(value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn screensaver_key_toggles_dimming_without_forwarding() {
+        let id = "test-screensaver-key";
+        let config = Box::leak(Box::new(Config {
+            screensaver_key: Some(0),
+            ..events_config(false).clone()
+        }));
+        let (device, mut events) = start_events(id, 1, config).await;
+
+        device.feed_input(11, 1);
+        assert!(no_more_events(&mut events, id).await);
+        assert_eq!(brightness_calls(&device), [config.idle_brightness]);
+
+        // Other buttons still work, but don't end a dimming asked for with the key
+        device.feed_input(1, 1);
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(10)", "ButtonUp(10)"]
+        );
+        assert!(brightness_calls(&device).is_empty());
+
+        device.feed_input(11, 1);
+        assert!(no_more_events(&mut events, id).await);
+        assert_eq!(brightness_calls(&device), [config.brightness]);
+    }
+
+    // Idle time is measured with std time, which doesn't pause
+    #[tokio::test]
+    async fn idle_device_is_dimmed_until_input() {
+        let id = "test-idle-dim";
+        let config = Box::leak(Box::new(Config {
+            idle_timeout_ms: 300,
+            ..events_config(false).clone()
+        }));
+        let (device, mut events) = start_events(id, 1, config).await;
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(brightness_calls(&device), [config.idle_brightness]);
+
+        // Input waking the device still reaches OpenDeck
+        device.feed_input(11, 1);
+        assert_eq!(next_events(&mut events, id, 1).await, ["ButtonDown(0)"]);
+        assert_eq!(brightness_calls(&device), [config.brightness]);
+    }
+
+    // Idle time is measured with std time, which doesn't pause
+    #[tokio::test]
+    async fn screensaver_key_and_idle_timer_dont_fight() {
+        let id = "test-screensaver-idle";
+        let config = Box::leak(Box::new(Config {
+            screensaver_key: Some(0),
+            idle_timeout_ms: 300,
+            ..events_config(false).clone()
+        }));
+        let (device, mut events) = start_events(id, 1, config).await;
+        let (awake, dimmed) = (config.brightness, config.idle_brightness);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(brightness_calls(&device), [dimmed]);
+
+        // Dismisses the idle dimming rather than dimming again
+        device.feed_input(11, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(brightness_calls(&device), [awake]);
+
+        // Idle timer leaves a dimming asked for with the key alone
+        device.feed_input(11, 1);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(brightness_calls(&device), [dimmed]);
+        assert!(no_more_events(&mut events, id).await);
+    }
+
+    /// Config for running [device_events_task] with a heartbeat every 50 ms
+    fn watchdog_config() -> &'static Config {
+        Box::leak(Box::new(Config {
+            watchdog_interval_ms: 50,
+            ..events_config(false).clone()
+        }))
+    }
+
+    // Time since the last read is measured with std time, which doesn't pause
+    #[tokio::test]
+    async fn silent_reader_with_dead_link_drops_device() {
+        let id = "test-watchdog-dead";
+        let device = register_scripted(id).await;
+        device.fail_next(|call| *call == DeviceCall::KeepAlive, usize::MAX);
+
+        // Reader of the scripted device never yields without input
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            device_events_task(id, watchdog_config()),
+        )
+        .await
+        .expect("Stalled reader wasn't detected")
+        .unwrap();
+
+        assert!(get_device(id).await.is_none());
+        let heartbeats = device
+            .calls()
+            .iter()
+            .filter(|call| **call == DeviceCall::KeepAlive)
+            .count();
+        assert_eq!(heartbeats, WATCHDOG_MAX_FAILED_HEARTBEATS as usize);
+    }
+
+    #[tokio::test]
+    async fn silent_reader_with_live_link_is_kept() {
+        let id = "test-watchdog-alive";
+        let device = register_scripted(id).await;
+
+        let task = tokio::spawn(device_events_task(id, watchdog_config()));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        assert!(!task.is_finished());
+        assert!(get_device(id).await.is_some());
+        assert!(device.calls().contains(&DeviceCall::KeepAlive));
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fed_press_is_forwarded_with_both_states_before_v3() {
+        let id = "test-events-v1";
+        let (device, mut events) = start_events(id, 1, events_config(true)).await;
+
+        device.feed_input(11, 1);
+
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(0)", "ButtonUp(0)"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fed_press_and_release_are_forwarded_from_v3() {
+        let id = "test-events-v3";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        device.feed_input(1, 1);
+        device.feed_input(1, 0);
+        device.feed_input(15, 1);
+        // Every key released
+        device.feed_input(0, 1);
+
+        assert_eq!(
+            next_events(&mut events, id, 4).await,
+            [
+                "ButtonDown(10)",
+                "ButtonUp(10)",
+                "ButtonDown(4)",
+                "ButtonUp(4)"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_button_is_released_only_once_reported_from_v3() {
+        let id = "test-events-hold";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        device.feed_input(11, 1);
+        assert_eq!(next_events(&mut events, id, 1).await, ["ButtonDown(0)"]);
+
+        // Held for longer than any tap
+        assert!(no_more_events(&mut events, id).await);
+
+        device.feed_input(11, 0);
+        assert_eq!(next_events(&mut events, id, 1).await, ["ButtonUp(0)"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fed_dial_input_is_forwarded() {
+        let id = "test-events-dial";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        device.feed_input(ENCODER_CW_INPUT, 1);
+        device.feed_input(ENCODER_CCW_INPUT, 1);
+        device.feed_input(ENCODER_PRESS_INPUT, 1);
+        device.feed_input(ENCODER_PRESS_INPUT, 0);
+
+        assert_eq!(
+            next_events(&mut events, id, 4).await,
+            [
+                "EncoderTwist(0, 1)",
+                "EncoderTwist(0, -1)",
+                "EncoderDown(0)",
+                "EncoderUp(0)"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_input_is_not_forwarded() {
+        let id = "test-events-malformed";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        device.feed_input(0xff, 1);
+
+        assert!(no_more_events(&mut events, id).await);
+    }
+
+    #[test]
+    fn updates_are_described_on_one_line() {
+        let described: Vec<String> = [
+            DeviceStateUpdate::ButtonDown(3),
+            DeviceStateUpdate::ButtonUp(3),
+            DeviceStateUpdate::EncoderDown(0),
+            DeviceStateUpdate::EncoderUp(0),
+            DeviceStateUpdate::EncoderTwist(1, -2),
+        ]
+        .iter()
+        .map(describe_update)
+        .collect();
+
+        assert_eq!(
+            described,
+            [
+                "key 3 down",
+                "key 3 up",
+                "encoder 0 down",
+                "encoder 0 up",
+                "encoder 1 twisted by -2"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_press_is_deduplicated_before_v3() {
+        let id = "test-dedup-v1";
+        let (device, mut events) = start_events(id, 1, events_config(true)).await;
+
+        device.feed_input(11, 1);
+        device.feed_input(11, 1);
+
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(0)", "ButtonUp(0)"]
+        );
+        assert!(no_more_events(&mut events, id).await);
+    }
+
+    // Dedup window is measured with std time, which doesn't pause
+    #[tokio::test]
+    async fn press_is_forwarded_again_after_dedup_window() {
+        let id = "test-dedup-window";
+        let (device, mut events) = start_events(id, 1, events_config(true)).await;
+
+        device.feed_input(14, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        device.feed_input(14, 1);
+
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(3)", "ButtonUp(3)"]
+        );
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        device.feed_input(14, 1);
+
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(3)", "ButtonUp(3)"]
+        );
+        assert!(no_more_events(&mut events, id).await);
+    }
+
+    // Counters are shared by every test, other tests only ever make them go up
+    #[tokio::test]
+    async fn metrics_follow_events_and_images() {
+        let id = "test-metrics";
+        let (device, mut events) = start_events(id, 1, events_config(true)).await;
+        let handle = get_device(id).await.unwrap();
+        let before = metrics::snapshot_metrics();
+
+        device.feed_input(11, 1);
+        device.feed_input(11, 1);
+        next_events(&mut events, id, 2).await;
+        handle_set_image(
+            &handle,
+            set_image_event(id, Some(0), Some(png_data_url([255, 0, 0]))),
+        )
+        .await
+        .unwrap();
+        handle_set_image(&handle, set_image_event(id, Some(0), None))
+            .await
+            .unwrap();
+
+        let after = metrics::snapshot_metrics();
+        assert!(after.events_deduped >= before.events_deduped + 2);
+        assert!(after.images_set > before.images_set);
+        assert!(after.images_cleared > before.images_cleared);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn released_press_is_not_deduplicated_from_v3() {
+        let id = "test-dedup-v3";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        for _ in 0..2 {
+            device.feed_input(11, 1);
+            device.feed_input(11, 0);
+        }
+
+        assert_eq!(
+            next_events(&mut events, id, 4).await,
+            [
+                "ButtonDown(0)",
+                "ButtonUp(0)",
+                "ButtonDown(0)",
+                "ButtonUp(0)"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn separate_presses_in_one_batch_are_not_deduplicated() {
+        let id = "test-dedup-batch";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        device.feed_updates(vec![
+            DeviceStateUpdate::ButtonDown(3),
+            DeviceStateUpdate::ButtonUp(3),
+            DeviceStateUpdate::ButtonDown(3),
+            DeviceStateUpdate::ButtonDown(3),
+            DeviceStateUpdate::ButtonUp(3),
+        ]);
+
+        // Only the press repeated without a release in between is dropped
+        assert_eq!(
+            next_events(&mut events, id, 4).await,
+            [
+                "ButtonDown(3)",
+                "ButtonUp(3)",
+                "ButtonDown(3)",
+                "ButtonUp(3)"
+            ]
+        );
+        assert!(no_more_events(&mut events, id).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn twists_are_never_deduplicated() {
+        let id = "test-dedup-twists";
+        let (device, mut events) = start_events(id, 1, events_config(true)).await;
+
+        for _ in 0..3 {
+            device.feed_input(ENCODER_CW_INPUT, 1);
+        }
+        device.feed_input(11, 1);
+        device.feed_input(11, 1);
+
+        assert_eq!(
+            next_events(&mut events, id, 5).await,
+            [
+                "EncoderTwist(0, 1)",
+                "EncoderTwist(0, 1)",
+                "EncoderTwist(0, 1)",
+                "ButtonDown(0)",
+                "ButtonUp(0)"
+            ]
+        );
+        assert!(no_more_events(&mut events, id).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nothing_is_deduplicated_with_dedup_disabled() {
+        let id = "test-dedup-disabled";
+        let (device, mut events) = start_events(id, 1, events_config(false)).await;
+
+        device.feed_input(11, 1);
+        device.feed_input(11, 1);
+
+        assert_eq!(next_events(&mut events, id, 4).await.len(), 4);
+    }
+
+    // Release times are measured with std time, which doesn't pause
+    #[tokio::test]
+    async fn contact_bounce_collapses_to_one_press() {
+        let id = "test-bounce";
+        let config = Box::leak(Box::new(Config {
+            bounce_window_ms: 200,
+            ..events_config(false).clone()
+        }));
+        let (device, mut events) = start_events(id, 3, config).await;
+
+        device.feed_updates(vec![
+            DeviceStateUpdate::ButtonDown(0),
+            DeviceStateUpdate::ButtonUp(0),
+            DeviceStateUpdate::ButtonDown(0),
+            DeviceStateUpdate::ButtonUp(0),
+        ]);
+        device.feed_updates(vec![DeviceStateUpdate::ButtonDown(1)]);
+
+        assert_eq!(
+            next_events(&mut events, id, 3).await,
+            ["ButtonDown(0)", "ButtonUp(0)", "ButtonDown(1)"]
+        );
+
+        // Press past the window is another press
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        device.feed_updates(vec![DeviceStateUpdate::ButtonDown(0)]);
+        assert_eq!(next_events(&mut events, id, 1).await, ["ButtonDown(0)"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled_device_forwards_nothing() {
+        let id = "test-disabled";
+        let (device, mut events) = start_events(id, 1, events_config(false)).await;
+
+        set_device_enabled(id, false).await;
+        assert_eq!(
+            device.take_calls(),
+            [DeviceCall::ClearAllButtonImages, DeviceCall::Flush]
+        );
+
+        device.feed_input(11, 1);
+        assert!(no_more_events(&mut events, id).await);
+
+        set_device_enabled(id, true).await;
+        device.feed_input(11, 1);
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(0)", "ButtonUp(0)"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snapshot_follows_held_buttons_from_v3() {
+        let id = "test-snapshot-v3";
+        let (device, mut events) = start_events(id, 3, events_config(true)).await;
+
+        device.feed_input(11, 1);
+        next_events(&mut events, id, 1).await;
+        assert!(button_snapshot(id).await.unwrap()[0]);
+
+        device.feed_input(11, 0);
+        next_events(&mut events, id, 1).await;
+        assert!(button_snapshot(id).await.unwrap().iter().all(|held| !held));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_snapshot_before_v3() {
+        let id = "test-snapshot-v1";
+        let (device, mut events) = start_events(id, 1, events_config(true)).await;
+
+        device.feed_input(11, 1);
+        next_events(&mut events, id, 2).await;
+
+        assert_eq!(button_snapshot(id).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buttons_held_at_connect_are_ignored_until_released() {
+        let id = "test-settle-held";
+        let at_connect = vec![
+            vec![DeviceStateUpdate::ButtonDown(0)],
+            vec![DeviceStateUpdate::ButtonUp(7)],
+            vec![DeviceStateUpdate::ButtonDown(1)],
+            vec![DeviceStateUpdate::ButtonUp(1)],
+            vec![DeviceStateUpdate::EncoderTwist(0, 1)],
+        ];
+        let (device, mut events) = start_events_with(id, 3, events_config(true), at_connect).await;
+
+        // Press made and released while settling is passed on, so is the dial
+        assert_eq!(
+            next_events(&mut events, id, 3).await,
+            ["ButtonDown(1)", "ButtonUp(1)", "EncoderTwist(0, 1)"]
+        );
+
+        device.feed_updates(vec![DeviceStateUpdate::ButtonUp(0)]);
+        device.feed_updates(vec![DeviceStateUpdate::ButtonDown(0)]);
+
+        assert_eq!(next_events(&mut events, id, 1).await, ["ButtonDown(0)"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn presses_at_connect_are_forwarded_before_v3() {
+        let id = "test-settle-v1";
+        let at_connect = vec![vec![
+            DeviceStateUpdate::ButtonDown(0),
+            DeviceStateUpdate::ButtonUp(0),
+        ]];
+        let (_device, mut events) = start_events_with(id, 1, events_config(true), at_connect).await;
+
+        assert_eq!(
+            next_events(&mut events, id, 2).await,
+            ["ButtonDown(0)", "ButtonUp(0)"]
+        );
+    }
+
+    /// Returns debug output of the queued updates, for comparing
+    fn queued(queue: &EventQueue) -> Vec<String> {
+        queue
+            .state()
+            .updates
+            .iter()
+            .map(|update| format!("{:?}", update))
+            .collect()
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_update() {
+        let queue = EventQueue::default();
+        for _ in 0..EVENT_QUEUE_CAPACITY {
+            assert!(queue.push(DeviceStateUpdate::EncoderTwist(0, 1)).is_empty());
+        }
+
+        let dropped = queue.push(DeviceStateUpdate::EncoderTwist(0, -1));
+
+        assert!(matches!(
+            dropped.as_slice(),
+            [DeviceStateUpdate::EncoderTwist(0, 1)]
+        ));
+        assert_eq!(queue.state().updates.len(), EVENT_QUEUE_CAPACITY);
+        assert_eq!(queued(&queue).last().unwrap(), "EncoderTwist(0, -1)");
+    }
+
+    #[test]
+    fn dropped_press_takes_queued_release_along() {
+        let queue = EventQueue::default();
+        queue.push(DeviceStateUpdate::ButtonDown(3));
+        queue.push(DeviceStateUpdate::ButtonUp(3));
+        for _ in 2..EVENT_QUEUE_CAPACITY {
+            queue.push(DeviceStateUpdate::EncoderTwist(0, 1));
+        }
+
+        let dropped = queue.push(DeviceStateUpdate::ButtonDown(4));
+
+        assert!(matches!(
+            dropped.as_slice(),
+            [
+                DeviceStateUpdate::ButtonDown(3),
+                DeviceStateUpdate::ButtonUp(3)
+            ]
+        ));
+        assert!(!queued(&queue).iter().any(|update| update.ends_with("(3)")));
+    }
+
+    #[test]
+    fn dropped_press_drops_release_arriving_later() {
+        let queue = EventQueue::default();
+        queue.push(DeviceStateUpdate::ButtonDown(3));
+        for _ in 1..EVENT_QUEUE_CAPACITY {
+            queue.push(DeviceStateUpdate::EncoderTwist(0, 1));
+        }
+
+        queue.push(DeviceStateUpdate::EncoderTwist(0, 1));
+        let dropped = queue.push(DeviceStateUpdate::ButtonUp(3));
+
+        assert!(matches!(
+            dropped.as_slice(),
+            [DeviceStateUpdate::ButtonUp(3)]
+        ));
+        assert!(!queued(&queue).iter().any(|update| update.ends_with("(3)")));
+
+        // Only the one release is dropped
+        assert_eq!(queue.push(DeviceStateUpdate::ButtonDown(3)).len(), 1);
+        assert_eq!(queue.push(DeviceStateUpdate::ButtonUp(3)).len(), 1);
+        assert_eq!(queued(&queue).last().unwrap(), "ButtonUp(3)");
+    }
+
+    #[test]
+    fn releases_are_never_dropped() {
+        let queue = EventQueue::default();
+        for key in 0..EVENT_QUEUE_CAPACITY as u8 {
+            queue.push(DeviceStateUpdate::ButtonUp(key));
+        }
+
+        assert!(queue.push(DeviceStateUpdate::ButtonUp(u8::MAX)).is_empty());
+        assert_eq!(queue.state().updates.len(), EVENT_QUEUE_CAPACITY + 1);
+    }
+
+    #[tokio::test]
+    async fn burst_is_forwarded_as_one_batch() {
+        let queue = EventQueue::default();
+        for key in 0..5 {
+            queue.push(DeviceStateUpdate::ButtonDown(key));
+            queue.push(DeviceStateUpdate::ButtonUp(key));
+        }
+
+        // [forward_events] locks the outbound event manager once per batch it takes
+        assert_eq!(queue.recv_all().await.unwrap().len(), 10);
+        assert!(queue.state().updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queue_closes_with_sender() {
+        let queue = Arc::new(EventQueue::default());
+        let sender = EventSender(queue.clone());
+
+        sender.push(DeviceStateUpdate::ButtonDown(0));
+        sender.push(DeviceStateUpdate::ButtonUp(0));
+        drop(sender);
+
+        assert_eq!(queue.recv_all().await.unwrap().len(), 2);
+        assert!(queue.recv_all().await.is_none());
+    }
+
+    /// Stand-in for OpenDeck that refuses key presses and deregistration and records the rest
+    #[derive(Default)]
+    struct RefusingOutbound {
+        sent: Vec<String>,
+    }
+
+    impl Outbound for RefusingOutbound {
+        async fn key_down(&mut self, _id: String, _key: u8) -> Result<(), OutboundError> {
+            Err("connection closed".into())
+        }
+
+        async fn key_up(&mut self, _id: String, key: u8) -> Result<(), OutboundError> {
+            self.sent.push(format!("key_up {}", key));
+            Ok(())
+        }
+
+        async fn encoder_down(&mut self, _id: String, encoder: u8) -> Result<(), OutboundError> {
+            self.sent.push(format!("encoder_down {}", encoder));
+            Ok(())
+        }
+
+        async fn encoder_up(&mut self, _id: String, encoder: u8) -> Result<(), OutboundError> {
+            self.sent.push(format!("encoder_up {}", encoder));
+            Ok(())
+        }
+
+        async fn encoder_change(
+            &mut self,
+            _id: String,
+            encoder: u8,
+            ticks: i16,
+        ) -> Result<(), OutboundError> {
+            self.sent
+                .push(format!("encoder_change {} {}", encoder, ticks));
+            Ok(())
+        }
+
+        async fn deregister_device(&mut self, _id: String) -> Result<(), OutboundError> {
+            Err("connection closed".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_send_doesnt_stop_forwarding() {
+        let mut outbound = RefusingOutbound::default();
+        let updates = vec![
+            DeviceStateUpdate::ButtonDown(0),
+            DeviceStateUpdate::ButtonUp(0),
+            DeviceStateUpdate::ButtonDown(1),
+            DeviceStateUpdate::EncoderTwist(0, 2),
+        ];
+
+        send_updates(
+            &mut outbound,
+            "test-refused-send",
+            updates,
+            &mut HashMap::new(),
+            &CONFIG,
+        )
+        .await;
+
+        assert_eq!(outbound.sent, ["key_up 0", "encoder_change 0 2"]);
+    }
+
+    #[tokio::test]
+    async fn failed_deregistration_is_not_fatal() {
+        let mut outbound = RefusingOutbound::default();
+
+        deregister_failed_device("test-refused-deregister", Some(&mut outbound)).await;
+    }
+
+    /// Registers a scripted device without running its event loop
+    async fn register_scripted(id: &str) -> SimulatedDevice {
+        let (handle, device) = scripted_device(id, 1);
+        DEVICES
+            .write()
+            .await
+            .insert(id.to_string(), Arc::new(handle));
+
+        device
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_images_and_errors_dont_deadlock() {
+        let id = "test-stress";
+        register_scripted(id).await;
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for position in 0..8u8 {
+            tasks.spawn(async move {
+                for i in 0..10u8 {
+                    if let Some(device) = get_device(id).await {
+                        let image = png_data_url([position * 30, i * 10, 0]);
+                        let event = set_image_event(id, Some(position), Some(image));
+                        handle_set_image(&device, event).await.ok();
+                    }
+                    set_device_brightness(id, i * 5).await.ok();
+                }
+            });
+        }
+        tasks.spawn(async move {
+            for _ in 0..10 {
+                handle_error(id, MirajazzError::DeviceNotFoundError.into()).await;
+                register_scripted(id).await;
+                tokio::task::yield_now().await;
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(10), tasks.join_all())
+            .await
+            .expect("Setting images while dropping the device deadlocked");
+
+        assert!(get_device(id).await.is_some());
+        remove_device(id).await;
+    }
+
+    /// Returns name of the device as listed by [connected_devices]
+    async fn connected_name(id: &str) -> Option<String> {
+        connected_devices()
+            .await
+            .into_iter()
+            .find_map(|(other, name)| (other == id).then_some(name))
+    }
+
+    #[tokio::test]
+    async fn registered_device_is_listed_until_removed() {
+        let id = "test-connected-devices";
+        let (handle, _device) = scripted_device(id, 1);
+
+        assert!(register_device(id, "Test Deck".to_string(), handle).await);
+        assert_eq!(connected_name(id).await.as_deref(), Some("Test Deck"));
+
+        remove_device(id).await;
+        assert_eq!(connected_name(id).await, None);
+    }
+
+    #[tokio::test]
+    async fn callbacks_see_connect_then_disconnect() {
+        let id = "test-device-callbacks";
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let seen = events.clone();
+        // Callbacks are global, so only events of this device are kept
+        on_device_event(move |event| match &event {
+            DeviceEvent::Connected(event_id) | DeviceEvent::Disconnected(event_id)
+                if event_id == id =>
+            {
+                seen.lock().unwrap().push(event)
+            }
+            _ => {}
+        });
+
+        let (handle, _device) = scripted_device(id, 1);
+        assert!(register_device(id, "Test Deck".to_string(), handle).await);
+        remove_device(id).await;
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                DeviceEvent::Connected(id.to_string()),
+                DeviceEvent::Disconnected(id.to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn panicking_task_is_cleaned_up() {
+        let id = "test-panicking-task";
+        let device = register_scripted(id).await;
+        let token = CancellationToken::new();
+        TOKENS.write().await.insert(id.to_string(), token.clone());
+
+        supervise(id, token.clone(), async { panic!("device task bug") }).await;
+
+        assert!(token.is_cancelled());
+        assert!(get_device(id).await.is_none());
+        assert!(!TOKENS.read().await.contains_key(id));
+        assert_eq!(device.calls().last(), Some(&DeviceCall::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn brightness_errors_are_returned_while_outbound_is_locked() {
+        let id = "test-brightness-error";
+        let device = register_scripted(id).await;
+        device.fail_next(|call| matches!(call, DeviceCall::SetBrightness(_)), 1);
+
+        // OpenDeck event handlers run with the outbound event manager locked
+        let _outbound = OUTBOUND_EVENT_MANAGER.lock().await;
+
+        let result = tokio::time::timeout(Duration::from_secs(5), set_device_brightness(id, 30))
+            .await
+            .expect("Setting brightness waited for the outbound event manager");
+
+        assert!(result.unwrap_err().is_fatal());
+        assert_eq!(BRIGHTNESS.read().await.get(id), None);
+        assert!(get_device(id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn brightness_is_recorded_once_set() {
+        let id = "test-brightness";
+        let device = register_scripted(id).await;
+
+        set_device_brightness(id, 130).await.unwrap();
+
+        assert_eq!(device.calls(), [DeviceCall::SetBrightness(100)]);
+        assert_eq!(get_device_brightness(id).await, Some(100));
+    }
+
+    #[tokio::test]
+    async fn brightness_is_reapplied_after_reconnect() {
+        let id = "test-brightness-reconnect";
+        register_scripted(id).await;
+        set_device_brightness(id, 30).await.unwrap();
+
+        assert!(!handle_error(id, MirajazzError::DeviceNotFoundError.into()).await);
+
+        let device = SimulatedDevice::scripted(1);
+        let brightness = initial_brightness(id, &Config::default()).await;
+        Kind::AMPGD6
+            .init_sequence(&device, brightness)
+            .await
+            .unwrap();
+
+        assert_eq!(brightness_calls(&device), [30]);
+        assert_eq!(
+            initial_brightness("test-brightness-never-set", &Config::default()).await,
+            Config::default().brightness
+        );
+    }
+
+    #[tokio::test]
+    async fn fatal_error_drops_device() {
+        let id = "test-fatal-error";
+        register_scripted(id).await;
+        let device = get_device(id).await.unwrap();
+        device
+            .images
+            .lock()
+            .await
+            .insert(0, ButtonImage::Decoded(DynamicImage::new_rgb8(8, 8)));
+
+        assert!(handle_error(id, MirajazzError::BadData.into()).await);
+        assert!(get_device(id).await.is_some());
+
+        assert!(!handle_error(id, MirajazzError::DeviceNotFoundError.into()).await);
+        assert!(get_device(id).await.is_none());
+        assert!(RESTORE_IMAGES.lock().await.contains_key(id));
+    }
+
+    #[tokio::test]
+    async fn init_sets_brightness_then_clears_and_flushes() {
+        for kind in KINDS {
+            let device = SimulatedDevice::scripted(kind.protocol_version());
+
+            assert_eq!(kind.init_sequence(&device, 50).await.unwrap(), 0);
+            assert_eq!(
+                device.calls(),
+                [
+                    DeviceCall::SetBrightness(50),
+                    DeviceCall::ClearAllButtonImages,
+                    DeviceCall::Flush
+                ]
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn init_commands_are_retried() {
+        let device = SimulatedDevice::scripted(1);
+        device.fail_next(|call| matches!(call, DeviceCall::SetBrightness(_)), 1);
+
+        assert_eq!(run_init_commands(&device, 50).await.unwrap(), 0);
+        assert_eq!(
+            device.calls(),
+            [
+                DeviceCall::SetBrightness(50),
+                DeviceCall::SetBrightness(50),
+                DeviceCall::ClearAllButtonImages,
+                DeviceCall::Flush
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_init_command_leaves_device_degraded() {
+        let device = SimulatedDevice::scripted(1);
+        device.fail_next(
+            |call| *call == DeviceCall::ClearAllButtonImages,
+            INIT_COMMAND_ATTEMPTS as usize,
+        );
+
+        assert_eq!(run_init_commands(&device, 50).await.unwrap(), 1);
+        assert_eq!(device.calls().last(), Some(&DeviceCall::Flush));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_failing_every_init_command_is_not_ready() {
+        let device = SimulatedDevice::scripted(1);
+        device.fail_next(|_| true, usize::MAX);
+
+        assert!(matches!(
+            run_init_commands(&device, 50).await,
+            Err(MirajazzError::DeviceNotFoundError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn removal_clears_the_device() {
+        let id = "test-removal-clear";
+        let device = register_scripted(id).await;
+
+        remove_device(id).await;
+
+        assert_eq!(
+            device.take_calls(),
+            [
+                DeviceCall::ClearAllButtonImages,
+                DeviceCall::Flush,
+                DeviceCall::Shutdown
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_clear_still_shuts_the_device_down() {
+        let device = SimulatedDevice::scripted(1);
+        device.fail_next(|call| *call == DeviceCall::ClearAllButtonImages, 1);
+
+        run_shutdown_commands(&device).await;
+
+        assert_eq!(device.calls().last(), Some(&DeviceCall::Shutdown));
+    }
+
+    /// Sets images on a registered scripted device, one per position
+    async fn set_images(id: &str, positions: &[u8]) {
+        let device = get_device(id).await.unwrap();
+        for (i, position) in positions.iter().enumerate() {
+            let image = png_data_url([i as u8 * 10, 0, 0]);
+            handle_set_image(&device, set_image_event(id, Some(*position), Some(image)))
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Returns the keys written since the last call, with the writes and flushes counted
+    fn restored_keys(device: &SimulatedDevice) -> (Vec<u8>, usize) {
+        let calls = device.take_calls();
+        let mut keys: Vec<u8> = calls
+            .iter()
+            .filter_map(|call| match call {
+                DeviceCall::WriteImage(key, _) => Some(*key),
+                _ => None,
+            })
+            .collect();
+        keys.sort();
+        let flushes = calls.iter().filter(|c| **c == DeviceCall::Flush).count();
+
+        (keys, flushes)
+    }
+
+    #[tokio::test]
+    async fn self_test_lights_every_button_in_turn() {
+        let id = "test-self-test";
+        let device = register_scripted(id).await;
+
+        run_self_test_with(id, Duration::ZERO).await.unwrap();
+
+        let calls = device.take_calls();
+        let written: Vec<u8> = calls
+            .iter()
+            .filter_map(|call| match call {
+                DeviceCall::WriteImage(key, _) => Some(*key),
+                _ => None,
+            })
+            .collect();
+        let cleared: Vec<u8> = calls
+            .iter()
+            .filter_map(|call| match call {
+                DeviceCall::ClearButtonImage(key) => Some(*key),
+                _ => None,
+            })
+            .collect();
+        let layout = Kind::AMPGD6.layout();
+        let in_opendeck_order: Vec<u8> = (0..layout.key_count() as u8)
+            .map(|position| opendeck_to_device(layout, position))
+            .collect();
+        assert_eq!(written, in_opendeck_order);
+        assert_eq!(cleared, in_opendeck_order);
+        assert!(matches!(calls.last(), Some(DeviceCall::Flush)));
+    }
+
+    #[tokio::test]
+    async fn images_are_restored_after_reconnect() {
+        let id = "test-restore-reconnect";
+        register_scripted(id).await;
+        set_images(id, &[0, 14]).await;
+
+        assert!(!handle_error(id, MirajazzError::DeviceNotFoundError.into()).await);
+
+        let device = register_scripted(id).await;
+        assert!(restore_images(id).await);
+
+        assert_eq!(restored_keys(&device), (vec![4, 10], 1));
+        assert!(!restore_images(id).await);
+    }
+
+    #[tokio::test]
+    async fn images_are_restored_after_removal() {
+        let id = "test-restore-removal";
+        register_scripted(id).await;
+        set_images(id, &[5]).await;
+
+        remove_device(id).await;
+        assert!(get_device(id).await.is_none());
+
+        let device = register_scripted(id).await;
+        assert!(restore_images(id).await);
+
+        assert_eq!(restored_keys(&device), (vec![5], 1));
+    }
+
+    #[tokio::test]
+    async fn expired_images_are_not_restored() {
+        let id = "test-restore-expired";
+        let device = register_scripted(id).await;
+        let since = Instant::now().checked_sub(RESTORE_IMAGES_TTL).unwrap();
+        let images = HashMap::from([(0, ButtonImage::Decoded(DynamicImage::new_rgb8(8, 8)))]);
+        RESTORE_IMAGES
+            .lock()
+            .await
+            .insert(id.to_string(), (since, images));
+
+        assert!(!restore_images(id).await);
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn opendeck_image_replaces_animation() {
+        let id = "test-animation-replaced";
+        let device = register_scripted(id).await;
+        let handle = get_device(id).await.unwrap();
+
+        handle_set_image(&handle, set_image_event(id, Some(0), Some(gif_data_url())))
+            .await
+            .unwrap();
+        assert!(animating(id, 0).await);
+
+        handle_set_image(
+            &handle,
+            set_image_event(id, Some(0), Some(png_data_url([0, 255, 0]))),
+        )
+        .await
+        .unwrap();
+        assert!(!animating(id, 0).await);
+
+        // No frames are written over the image once it's set
+        let writes = device.calls().len();
+        tokio::time::sleep(ANIMATION_MIN_FRAME_DELAY * 3).await;
+
+        assert_eq!(device.calls().len(), writes);
+        assert!(matches!(
+            handle.images.lock().await.get(&0),
+            Some(ButtonImage::Encoded(_, image::ImageFormat::Png))
+        ));
+    }
+
+    #[tokio::test]
+    async fn image_is_ignored_while_animating() {
+        let id = "test-animation-ignore";
+        register_scripted(id).await;
+        let handle = get_device(id).await.unwrap();
+        handle_set_image(&handle, set_image_event(id, Some(0), Some(gif_data_url())))
+            .await
+            .unwrap();
+
+        let held = hold_back_for_animation(
+            id,
+            0,
+            b"png",
+            image::ImageFormat::Png,
+            AnimationUpdates::Ignore,
+        )
+        .await;
+
+        assert!(held);
+        assert!(animating(id, 0).await);
+        assert!(
+            !QUEUED_IMAGES
+                .lock()
+                .await
+                .contains_key(&(id.to_string(), 0))
+        );
+        assert!(
+            !hold_back_for_animation(
+                id,
+                1,
+                b"png",
+                image::ImageFormat::Png,
+                AnimationUpdates::Ignore
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_image_replaces_animation_at_its_end() {
+        let id = "test-animation-queue";
+        register_scripted(id).await;
+        let handle = get_device(id).await.unwrap();
+        handle_set_image(&handle, set_image_event(id, Some(0), Some(gif_data_url())))
+            .await
+            .unwrap();
+
+        let mut png = vec![];
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        assert!(
+            hold_back_for_animation(
+                id,
+                0,
+                &png,
+                image::ImageFormat::Png,
+                AnimationUpdates::Queue
+            )
+            .await
+        );
+        assert!(animating(id, 0).await);
+
+        tokio::time::sleep(ANIMATION_MIN_FRAME_DELAY * 5).await;
+
+        assert!(!animating(id, 0).await);
+        assert!(matches!(
+            handle.images.lock().await.get(&0),
+            Some(ButtonImage::Encoded(_, image::ImageFormat::Png))
+        ));
+    }
+
+    #[tokio::test]
+    async fn clearing_drops_queued_image() {
+        let id = "test-animation-queue-clear";
+        register_scripted(id).await;
+        let handle = get_device(id).await.unwrap();
+        handle_set_image(&handle, set_image_event(id, Some(0), Some(gif_data_url())))
+            .await
+            .unwrap();
+        hold_back_for_animation(
+            id,
+            0,
+            b"png",
+            image::ImageFormat::Png,
+            AnimationUpdates::Queue,
+        )
+        .await;
+
+        handle_set_image(&handle, set_image_event(id, Some(0), None))
+            .await
+            .unwrap();
+
+        assert!(!animating(id, 0).await);
+        assert!(
+            !QUEUED_IMAGES
+                .lock()
+                .await
+                .contains_key(&(id.to_string(), 0))
+        );
+    }
+
+    #[cfg(feature = "text")]
+    #[tokio::test]
+    async fn debug_overlay_keeps_button_image() {
+        let id = "test-debug-overlay";
+        let device = register_scripted(id).await;
+        let handle = get_device(id).await.unwrap();
+        handle_set_image(
+            &handle,
+            set_image_event(id, Some(14), Some(png_data_url([0, 255, 0]))),
+        )
+        .await
+        .unwrap();
+        let image = device.take_calls();
+
+        set_debug_overlay(id, true).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let overlay = device.take_calls();
+        assert!(matches!(
+            overlay.as_slice(),
+            [DeviceCall::WriteImage(4, _), DeviceCall::Flush]
+        ));
+        assert_ne!(overlay[0], image[0]);
+        assert!(matches!(
+            handle.images.lock().await.get(&14),
+            Some(ButtonImage::Encoded(_, image::ImageFormat::Png))
+        ));
+
+        set_debug_overlay(id, false).await;
+        assert_eq!(device.take_calls(), image);
+        assert!(!DEBUG_OVERLAYS.lock().await.contains_key(id));
+
+        // Nothing is drawn once it's hidden
+        tokio::time::sleep(DEBUG_OVERLAY_REFRESH * 2).await;
+        assert!(device.calls().is_empty());
+    }
+
+    #[cfg(feature = "text")]
+    #[tokio::test]
+    async fn hiding_debug_overlay_clears_empty_button() {
+        let id = "test-debug-overlay-empty";
+        let device = register_scripted(id).await;
+
+        set_debug_overlay(id, true).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        device.take_calls();
+        set_debug_overlay(id, false).await;
+
+        assert_eq!(
+            device.take_calls(),
+            [DeviceCall::ClearButtonImage(4), DeviceCall::Flush]
+        );
+    }
 }
@@ -0,0 +1,121 @@
+use std::{fmt, io, path::PathBuf};
+
+use data_url::{DataUrlError, forgiving_base64::InvalidBase64};
+use image::ImageError;
+use mirajazz::error::MirajazzError;
+
+/// Errors of the device layer
+#[derive(Debug)]
+pub enum DeviceError {
+    /// Error reported by mirajazz while talking to the device
+    Mirajazz(MirajazzError),
+    /// Image sent by OpenDeck isn't a valid data url
+    DataUrl(DataUrlError),
+    /// Body of the image data url isn't valid base64
+    Base64(InvalidBase64),
+    /// Image couldn't be decoded
+    Image(ImageError),
+    /// Button position is outside the keypad
+    PositionOutOfRange(u8),
+    /// Image file couldn't be read, with its path
+    File(PathBuf, io::Error),
+}
+
+impl DeviceError {
+    /// Returns true if the error means the connection to the device is lost,
+    /// all the others only affect the single request that caused them
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Self::Mirajazz(MirajazzError::ImageError(_) | MirajazzError::BadData) => false,
+            Self::Mirajazz(_) => true,
+            Self::DataUrl(_)
+            | Self::Base64(_)
+            | Self::Image(_)
+            | Self::PositionOutOfRange(_)
+            | Self::File(..) => false,
+        }
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mirajazz(err) => write!(f, "{}", err),
+            Self::DataUrl(err) => write!(f, "Malformed image data url: {}", err),
+            Self::Base64(err) => write!(f, "Failed to decode image data url body: {}", err),
+            Self::Image(err) => write!(f, "Failed to decode image: {}", err),
+            Self::PositionOutOfRange(position) => {
+                write!(f, "Button position {} is out of range", position)
+            }
+            Self::File(path, err) => {
+                write!(f, "Failed to read image file {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<MirajazzError> for DeviceError {
+    fn from(err: MirajazzError) -> Self {
+        Self::Mirajazz(err)
+    }
+}
+
+impl From<DataUrlError> for DeviceError {
+    fn from(err: DataUrlError) -> Self {
+        Self::DataUrl(err)
+    }
+}
+
+impl From<InvalidBase64> for DeviceError {
+    fn from(err: InvalidBase64) -> Self {
+        Self::Base64(err)
+    }
+}
+
+impl From<ImageError> for DeviceError {
+    fn from(err: ImageError) -> Self {
+        Self::Image(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_url::forgiving_base64;
+
+    use super::*;
+
+    fn image_error() -> ImageError {
+        image::load_from_memory(b"not an image").unwrap_err()
+    }
+
+    #[test]
+    fn lost_connection_is_fatal() {
+        for err in [
+            MirajazzError::DeviceNotFoundError,
+            MirajazzError::InvalidDeviceError,
+            MirajazzError::PoisonError,
+            MirajazzError::UnsupportedOperation,
+        ] {
+            assert!(DeviceError::from(err).is_fatal());
+        }
+    }
+
+    #[test]
+    fn errors_of_a_single_request_are_not_fatal() {
+        let errors = [
+            DeviceError::from(MirajazzError::BadData),
+            DeviceError::from(MirajazzError::ImageError(image_error())),
+            DeviceError::from(DataUrlError::NotADataUrl),
+            DeviceError::from(forgiving_base64::decode_to_vec(b"a").unwrap_err()),
+            DeviceError::from(image_error()),
+            DeviceError::PositionOutOfRange(15),
+            DeviceError::File("missing.png".into(), io::ErrorKind::NotFound.into()),
+        ];
+
+        for err in errors {
+            assert!(!err.is_fatal(), "{}", err);
+        }
+    }
+}
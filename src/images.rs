@@ -0,0 +1,437 @@
+use std::{collections::VecDeque, io::Cursor, sync::Arc, time::Duration};
+
+use image::{
+    AnimationDecoder, ColorType, DynamicImage, GenericImageView, ImageError, ImageReader, Rgb,
+    RgbImage, Rgba, RgbaImage,
+    codecs::{bmp::BmpEncoder, gif::GifDecoder, jpeg::JpegEncoder},
+    imageops::{FilterType, overlay},
+};
+use mirajazz::{
+    error::MirajazzError,
+    types::{ImageFormat, ImageMirroring, ImageMode, ImageRotation},
+};
+
+/// Encodes image into the data the device expects, resizing, rotating and mirroring it
+/// according to the image format, JPEG is encoded with the given quality
+///
+/// Encoding runs on the blocking thread pool, so it works on any runtime without stalling
+/// the tasks of other devices
+pub async fn encode_image(
+    image_format: ImageFormat,
+    image: DynamicImage,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>, MirajazzError> {
+    tokio::task::spawn_blocking(move || encode_image_impl(image_format, image, jpeg_quality))
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+fn encode_image_impl(
+    image_format: ImageFormat,
+    image: DynamicImage,
+    jpeg_quality: u8,
+) -> Result<Vec<u8>, MirajazzError> {
+    let (width, height) = image_format.size;
+
+    let image = fit_to_size(image, width as u32, height as u32);
+
+    let image = match image_format.rotation {
+        ImageRotation::Rot0 => image,
+        ImageRotation::Rot90 => image.rotate90(),
+        ImageRotation::Rot180 => image.rotate180(),
+        ImageRotation::Rot270 => image.rotate270(),
+    };
+
+    let image = match image_format.mirror {
+        ImageMirroring::None => image,
+        ImageMirroring::X => image.fliph(),
+        ImageMirroring::Y => image.flipv(),
+        ImageMirroring::Both => image.fliph().flipv(),
+    };
+
+    let (width, height) = (image.width(), image.height());
+    let image_data = image.into_rgb8().into_raw();
+
+    let mut buf = Vec::new();
+
+    match image_format.mode {
+        ImageMode::None => {}
+        ImageMode::BMP => {
+            BmpEncoder::new(&mut buf).encode(&image_data, width, height, ColorType::Rgb8.into())?;
+        }
+        ImageMode::JPEG => {
+            JpegEncoder::new_with_quality(&mut buf, jpeg_quality).encode(
+                &image_data,
+                width,
+                height,
+                ColorType::Rgb8.into(),
+            )?;
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Scales image to fit into the given size preserving aspect ratio, letterboxing it
+/// on a black background if the aspect ratio differs
+pub fn fit_to_size(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    if image.dimensions() == (width, height) {
+        return image;
+    }
+
+    let resized = image.resize(width, height, FilterType::Lanczos3);
+
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+    let x = (width - resized.width()) / 2;
+    let y = (height - resized.height()) / 2;
+    overlay(&mut canvas, &resized, x as i64, y as i64);
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Returns placeholder for images that couldn't be decoded, a red cross on black
+pub fn broken_image(width: u32, height: u32) -> DynamicImage {
+    let (w, h) = (width as i64, height as i64);
+    let thickness = (w.min(h) / 12).max(1);
+    let margin = w.min(h) / 5;
+
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as i64, y as i64);
+
+        // Distance to each diagonal, scaled by the longer side so non-square screens work too
+        let on_diagonal = |x: i64| (x * h - y * w).abs() < thickness * w.max(h);
+        let inside = (margin..w - margin).contains(&x) && (margin..h - margin).contains(&y);
+
+        if inside && (on_diagonal(x) || on_diagonal(w - 1 - x)) {
+            Rgb([220, 0, 0])
+        } else {
+            Rgb([0, 0, 0])
+        }
+    });
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Font text labels are rendered with, see [render_text]
+#[cfg(feature = "text")]
+static FONT: std::sync::LazyLock<ab_glyph::FontRef<'static>> = std::sync::LazyLock::new(|| {
+    ab_glyph::FontRef::try_from_slice(include_bytes!("../assets/fonts/DejaVuSans-Bold.ttf"))
+        .expect("Bundled font is valid")
+});
+
+/// Renders text centered on a black image of the given size, each line of it centered
+/// on its own, text that doesn't fit is cut off at the edges
+#[cfg(feature = "text")]
+pub fn render_text(
+    text: &str,
+    width: u32,
+    height: u32,
+    font_size: f32,
+    color: [u8; 3],
+) -> DynamicImage {
+    use ab_glyph::{Font, PxScale, ScaleFont, point};
+
+    let font = FONT.as_scaled(PxScale::from(font_size));
+    let lines: Vec<&str> = text.lines().collect();
+
+    let line_height = font.height() + font.line_gap();
+    let text_height = line_height * lines.len() as f32 - font.line_gap();
+    let mut baseline = (height as f32 - text_height) / 2.0 + font.ascent();
+
+    let mut image = RgbImage::new(width, height);
+
+    for line in lines {
+        let glyphs: Vec<_> = line.chars().map(|c| font.glyph_id(c)).collect();
+        let line_width = glyphs.iter().map(|&id| font.h_advance(id)).sum::<f32>()
+            + glyphs
+                .windows(2)
+                .map(|pair| font.kern(pair[0], pair[1]))
+                .sum::<f32>();
+
+        let mut x = (width as f32 - line_width) / 2.0;
+        let mut previous = None;
+
+        for id in glyphs {
+            if let Some(previous) = previous {
+                x += font.kern(previous, id);
+            }
+            previous = Some(id);
+
+            let glyph = id.with_scale_and_position(font.scale(), point(x, baseline));
+            x += font.h_advance(id);
+
+            let Some(outlined) = FONT.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+
+            outlined.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+
+                if let (Ok(px), Ok(py)) = (u32::try_from(px), u32::try_from(py))
+                    && px < width
+                    && py < height
+                {
+                    let pixel = image.get_pixel_mut(px, py);
+                    for (channel, &target) in pixel.0.iter_mut().zip(&color) {
+                        *channel = (*channel).max((target as f32 * coverage.min(1.0)) as u8);
+                    }
+                }
+            });
+        }
+
+        baseline += line_height;
+    }
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Returns true if the source data can be sent to the device as is, which is only the case for
+/// a JPEG that already has the right size and needs no rotation or mirroring
+pub fn can_pass_through(
+    image_format: ImageFormat,
+    source_format: image::ImageFormat,
+    data: &[u8],
+) -> bool {
+    if !matches!(image_format.mode, ImageMode::JPEG)
+        || source_format != image::ImageFormat::Jpeg
+        || !matches!(image_format.rotation, ImageRotation::Rot0)
+        || !matches!(image_format.mirror, ImageMirroring::None)
+    {
+        return false;
+    }
+
+    let (width, height) = image_format.size;
+
+    ImageReader::with_format(Cursor::new(data), source_format)
+        .into_dimensions()
+        .is_ok_and(|dimensions| dimensions == (width as u32, height as u32))
+}
+
+/// Frames of an animation in the data the device expects, together with the delay after each
+/// of them
+pub type EncodedFrames = Vec<(Vec<u8>, Duration)>;
+
+/// Encoded frames of the animations shown last, so showing one of them again, e.g. when
+/// switching back to a profile, doesn't decode and encode every frame again
+///
+/// Keeps at most `capacity` animations, the one used longest ago is dropped to make room
+pub struct FrameCache {
+    capacity: usize,
+    // Least recently used first
+    entries: VecDeque<(u64, Arc<EncodedFrames>)>,
+}
+
+impl FrameCache {
+    /// Creates an empty cache of up to `capacity` animations, 0 keeps none
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns frames kept under the key, marking them as used last
+    pub fn get(&mut self, key: u64) -> Option<Arc<EncodedFrames>> {
+        let index = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(index)?;
+        let frames = entry.1.clone();
+        self.entries.push_back(entry);
+
+        Some(frames)
+    }
+
+    /// Keeps frames under the key, dropping the ones used longest ago if the cache is full
+    pub fn insert(&mut self, key: u64, frames: Arc<EncodedFrames>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.entries.retain(|(k, _)| *k != key);
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, frames));
+    }
+
+    /// Returns number of animations kept
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no animations are kept
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Decodes all the frames of a GIF together with the delay after each of them
+pub fn decode_gif_frames(data: &[u8]) -> Result<Vec<(DynamicImage, Duration)>, ImageError> {
+    let frames = GifDecoder::new(Cursor::new(data))?
+        .into_frames()
+        .collect_frames()?;
+
+    Ok(frames
+        .into_iter()
+        .map(|frame| {
+            let delay = Duration::from(frame.delay());
+
+            (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Format of a 105x105 JPEG screen that's mounted upright
+    const UPRIGHT_JPEG: ImageFormat = ImageFormat {
+        mode: ImageMode::JPEG,
+        size: (105, 105),
+        rotation: ImageRotation::Rot0,
+        mirror: ImageMirroring::None,
+    };
+
+    fn encoded(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+        let mut bytes = vec![];
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+
+        bytes
+    }
+
+    #[tokio::test]
+    async fn oversized_image_is_scaled_to_device_size() {
+        let image = DynamicImage::new_rgb8(512, 512);
+
+        let data = encode_image(UPRIGHT_JPEG, image, 85).await.unwrap();
+
+        let encoded = image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).unwrap();
+        assert_eq!(encoded.dimensions(), (105, 105));
+    }
+
+    #[tokio::test]
+    async fn undersized_image_is_scaled_to_device_size() {
+        let image = DynamicImage::new_rgb8(40, 40);
+
+        let data = encode_image(UPRIGHT_JPEG, image, 85).await.unwrap();
+
+        let encoded = image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg).unwrap();
+        assert_eq!(encoded.dimensions(), (105, 105));
+    }
+
+    #[tokio::test]
+    async fn oversized_image_of_other_aspect_ratio_is_letterboxed_to_device_size() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(420, 210, Rgba([255; 4])));
+
+        let data = encode_image(UPRIGHT_JPEG, image, 85).await.unwrap();
+
+        let encoded = image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(encoded.dimensions(), (105, 105));
+        assert!(encoded.get_pixel(52, 5).0.iter().all(|&c| c < 32));
+        assert!(encoded.get_pixel(52, 52).0.iter().all(|&c| c > 224));
+    }
+
+    #[test]
+    fn image_of_other_aspect_ratio_is_letterboxed() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(50, 25, Rgba([255; 4])));
+
+        let fitted = fit_to_size(image, 100, 100);
+
+        assert_eq!(fitted.dimensions(), (100, 100));
+        assert_eq!(fitted.get_pixel(50, 10), Rgba([0, 0, 0, 255]));
+        assert_eq!(fitted.get_pixel(50, 50), Rgba([255; 4]));
+    }
+
+    #[cfg(feature = "text")]
+    #[test]
+    fn text_is_rendered_in_its_color_on_black() {
+        let image = render_text("Hi\nthere", 105, 105, 20.0, [255, 255, 0]).to_rgb8();
+
+        assert_eq!(image.dimensions(), (105, 105));
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0]);
+        assert!(image.pixels().any(|pixel| pixel.0 == [255, 255, 0]));
+        assert!(image.pixels().all(|pixel| pixel.0[2] == 0));
+    }
+
+    #[test]
+    fn jpeg_of_device_size_passes_through() {
+        let jpeg = encoded(105, 105, image::ImageFormat::Jpeg);
+
+        assert!(can_pass_through(
+            UPRIGHT_JPEG,
+            image::ImageFormat::Jpeg,
+            &jpeg
+        ));
+    }
+
+    #[test]
+    fn other_images_are_reencoded() {
+        let jpeg = encoded(105, 105, image::ImageFormat::Jpeg);
+        let rotated = ImageFormat {
+            rotation: ImageRotation::Rot180,
+            ..UPRIGHT_JPEG
+        };
+        let bmp = ImageFormat {
+            mode: ImageMode::BMP,
+            ..UPRIGHT_JPEG
+        };
+
+        assert!(!can_pass_through(rotated, image::ImageFormat::Jpeg, &jpeg));
+        assert!(!can_pass_through(bmp, image::ImageFormat::Jpeg, &jpeg));
+        assert!(!can_pass_through(
+            UPRIGHT_JPEG,
+            image::ImageFormat::Jpeg,
+            &encoded(100, 100, image::ImageFormat::Jpeg)
+        ));
+        assert!(!can_pass_through(
+            UPRIGHT_JPEG,
+            image::ImageFormat::Png,
+            &encoded(105, 105, image::ImageFormat::Png)
+        ));
+    }
+
+    fn frames(byte: u8) -> Arc<EncodedFrames> {
+        Arc::new(vec![(vec![byte], Duration::from_millis(100))])
+    }
+
+    #[test]
+    fn frame_cache_evicts_least_recently_used() {
+        let mut cache = FrameCache::new(2);
+        cache.insert(1, frames(1));
+        cache.insert(2, frames(2));
+
+        // Using the first one makes the second one the oldest
+        assert!(cache.get(1).is_some());
+        cache.insert(3, frames(3));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap()[0].0, [1]);
+        assert_eq!(cache.get(3).unwrap()[0].0, [3]);
+    }
+
+    #[test]
+    fn frame_cache_replaces_same_key() {
+        let mut cache = FrameCache::new(2);
+        cache.insert(1, frames(1));
+        cache.insert(1, frames(4));
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(1).unwrap()[0].0, [4]);
+    }
+
+    #[test]
+    fn empty_frame_cache_keeps_nothing() {
+        let mut cache = FrameCache::new(0);
+        cache.insert(1, frames(1));
+
+        assert!(cache.is_empty());
+        assert!(cache.get(1).is_none());
+    }
+}
@@ -1,105 +1,478 @@
+use std::sync::LazyLock;
+
 use mirajazz::{error::MirajazzError, types::DeviceInput};
 
-use crate::mappings::KEY_COUNT;
+use crate::{
+    CONFIG,
+    mappings::{
+        AMPGD6_LAYOUT, ENCODER_CCW_INPUT, ENCODER_CW_INPUT, ENCODER_PRESS_INPUT, Kind, Layout,
+    },
+};
+
+/// Function turning raw firmware input into device input, mirajazz takes it as a plain
+/// function without any device context, so every kind gets one of its own, see [Kind::input_processor]
+pub type InputProcessor = fn(u8, u8) -> Result<DeviceInput, MirajazzError>;
 
+/// Turns raw input of the original D6 firmware into device input, see [process_input_with]
+///
+/// Every byte is handled without panicking, with the 1-based D6 firmware:
+/// - `0` reports every key released
+/// - `1..=15` are the keys, so 15 is the last key and not a sentinel
+/// - [ENCODER_CCW_INPUT], [ENCODER_CW_INPUT] and [ENCODER_PRESS_INPUT] are the dial
+/// - anything else is [MirajazzError::BadData]
 pub fn process_input(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    log::debug!("Processing input: {}, {}", input, state);
+    let kind = Kind::AMPGD6;
+
+    process_input_with(
+        input,
+        state,
+        kind.layout(),
+        kind.index_base(),
+        &INVERSE_KEY_MAP,
+    )
+}
+
+/// Turns raw input of the v2 D6 firmware into device input, see [process_input]
+pub fn process_v2_input(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+    let kind = Kind::AMPGD6V2;
 
-    match input as usize {
-        (0..=KEY_COUNT) => read_button_press(input, state),
+    process_input_with(
+        input,
+        state,
+        kind.layout(),
+        kind.index_base(),
+        &INVERSE_KEY_MAP,
+    )
+}
+
+/// Turns raw firmware input into device input for a device with the given layout, reporting
+/// keys as `index_base..index_base + key_count` and mapping them to OpenDeck positions with
+/// the inverse key map, see [invert_key_map]
+///
+/// Firmware counting keys from 1 uses 0 to report every key released, inputs that are neither
+/// a key nor the dial are [MirajazzError::BadData]
+pub fn process_input_with(
+    input: u8,
+    state: u8,
+    layout: Layout,
+    index_base: u8,
+    inverse_key_map: &[u8],
+) -> Result<DeviceInput, MirajazzError> {
+    log::trace!("Processing input: {}, {}", input, state);
+
+    let key_end = index_base as usize + layout.key_count();
+
+    match input {
+        ENCODER_CCW_INPUT | ENCODER_CW_INPUT => read_encoder_value(input, layout),
+        ENCODER_PRESS_INPUT => read_encoder_press(state, layout),
+        _ if (input as usize) < key_end => {
+            read_button_press(input, state, layout, index_base, inverse_key_map)
+        }
         _ => Err(MirajazzError::BadData),
     }
 }
 
-fn read_button_states(states: &[u8]) -> Vec<bool> {
-    let mut bools = vec![];
+/// Reads pressed state of every key from a state buffer, keys missing from a short buffer
+/// are reported as released
+fn read_button_states(states: &[u8], key_count: usize) -> Vec<bool> {
+    if states.len() < key_count + 1 {
+        log::warn!(
+            "Button state buffer is too short: {} bytes, expected {}",
+            states.len(),
+            key_count + 1
+        );
+    }
+
+    (0..key_count)
+        .map(|i| states.get(i + 1).copied().unwrap_or(0) != 0)
+        .collect()
+}
+
+/// Device to OpenDeck key index mapping of the D6, the inverse of `key_map` from the config
+static INVERSE_KEY_MAP: LazyLock<Vec<u8>> =
+    LazyLock::new(|| invert_key_map(&CONFIG.key_map, AMPGD6_LAYOUT));
 
-    for i in 0..KEY_COUNT {
-        bools.push(states[i + 1] != 0);
+/// Builds inverse of OpenDeck to device key index mapping for the layout, so `inverse[map[k]] == k`
+pub fn invert_key_map(map: &[u8], layout: Layout) -> Vec<u8> {
+    let key_count = layout.key_count();
+    let mut inverse = vec![u8::MAX; key_count];
+
+    for (opendeck_index, &device_index) in map.iter().enumerate() {
+        if let Some(slot) = inverse.get_mut(device_index as usize) {
+            *slot = opendeck_index as u8;
+        }
     }
 
-    bools
+    debug_assert!(
+        map.len() == key_count && inverse.iter().all(|&k| (k as usize) < key_count),
+        "Key map is not a permutation of 0..{}: {:?}",
+        key_count,
+        map
+    );
+
+    inverse
 }
 
 /// Converts opendeck key index to device key index
 /// For 3x5 layout (15 buttons), OpenDeck indexes: 0-14
-/// OpenDeck layout (row-major): 
+/// OpenDeck layout (row-major):
 /// Row 1: 0, 1, 2, 3, 4
 /// Row 2: 5, 6, 7, 8, 9
 /// Row 3: 10, 11, 12, 13, 14
-/// 
-/// AMPGD6 mapping - trying ss550 mapping pattern first, adjust if needed
-/// This maps OpenDeck positions to device button indexes
-pub fn opendeck_to_device(key: u8) -> u8 {
-    if key < KEY_COUNT as u8 {
-        // Try ss550-like mapping: [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4]
-        // This means: OpenDeck 0 -> Device 10, OpenDeck 1 -> Device 11, etc.
-        [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4][key as usize]
+///
+/// Uses `key_map` from the config, by default the ss550-like mapping
+/// [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4]
+/// This means: OpenDeck 0 -> Device 10, OpenDeck 1 -> Device 11, etc.
+///
+/// Keys outside of the layout are returned as they are
+pub fn opendeck_to_device(layout: Layout, key: u8) -> u8 {
+    if (key as usize) < layout.key_count() {
+        CONFIG.key_map.get(key as usize).copied().unwrap_or(key)
     } else {
         key
     }
 }
 
 /// Converts device key index to opendeck key index
-/// Device sends 1-based indexes (1-15), we convert to 0-based OpenDeck indexes (0-14)
-/// 
-/// User testing shows:
-/// - Image 0 -> Action 10 (when pressed) - WRONG, should be Action 0
-/// - Image 10 -> Action 0 (when pressed) - WRONG, should be Action 10
-/// 
-/// opendeck_to_device: [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4]
-/// This means: Device 10 shows image 0, Device 0 shows image 10
-/// 
-/// For events: When pressing Device 10 (shows image 0), we want action 0 to trigger
-/// When pressing Device 0 (shows image 10), we want action 10 to trigger
-/// 
-/// So: device_to_opendeck(10) should return 0, device_to_opendeck(0) should return 10
-/// 
-/// The correct inverse mapping: find where each device index appears in opendeck_to_device
-/// Device 0 appears at position 10 -> should return 10 (but we want 0 for image 0...)
-/// Wait, that's confusing. Let me think:
-/// 
-/// If Device 10 shows image 0, and we want pressing Device 10 to trigger action 0,
-/// then device_to_opendeck(10) must return 0.
-/// 
-/// The inverse of [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4] is:
-/// Device 0 -> OpenDeck 10, Device 1 -> OpenDeck 11, ..., Device 10 -> OpenDeck 0, Device 11 -> OpenDeck 1, ...
-/// Which is: [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4]
-/// 
-/// But that's what we had and it gave wrong results. The issue might be that the device
-/// sends a different index than what we use for images. Let's create a mapping based on the actual behavior.
-pub fn device_to_opendeck(key: usize) -> usize {
-    // Try the ss550 approach: simple linear mapping
-    // The device sends 1-based indexes (1-15), we convert to 0-based (0-14)
-    // For ss550, this works because the device sends linear indexes for events
-    // even though images use a mapped layout
-    let result = key - 1;
-    log::info!("device_to_opendeck: device_index_1based={}, opendeck_index={}", key, result);
+/// Device sends indexes starting at `index_base` (1-15 on the D6), we convert to 0-based
+/// OpenDeck indexes (0-14)
+///
+/// The firmware reports presses using the same slot numbering that images are written to
+/// (mirajazz sends images to slot `device_index + 1`), so this is the inverse of [opendeck_to_device]
+/// after the shift to 0-based: pressing the key that shows image N triggers action N
+///
+/// With the default mapping: device 11 -> OpenDeck 0, device 1 -> OpenDeck 10, device 6 -> OpenDeck 5
+///
+/// Returns `None` for indexes below `index_base` and past the inverse key map, which don't belong to any key
+pub fn device_to_opendeck_index(key: u8, index_base: u8, inverse_key_map: &[u8]) -> Option<u8> {
+    // Convert to 0-based (0-14) and look it up in the inverse table, so both directions
+    // use the same mapping
+    let result = inverse_key_map
+        .get(key.checked_sub(index_base)? as usize)
+        .copied()
+        .filter(|&index| (index as usize) < inverse_key_map.len());
+    log::trace!(
+        "device_to_opendeck_index: device_index={}, index_base={}, opendeck_index={:?}",
+        key,
+        index_base,
+        result
+    );
     result
 }
 
-fn read_button_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+fn read_button_press(
+    input: u8,
+    state: u8,
+    layout: Layout,
+    index_base: u8,
+    inverse_key_map: &[u8],
+) -> Result<DeviceInput, MirajazzError> {
+    let key_count = layout.key_count();
     let mut button_states = vec![0x01];
-    button_states.extend(vec![0u8; KEY_COUNT + 1]);
+    button_states.extend(vec![0u8; key_count + 1]);
 
-    if input == 0 {
+    // 0 is below the first key of 1-based firmware, which uses it to report everything released
+    if index_base > 0 && input == 0 {
         return Ok(DeviceInput::ButtonStateChange(read_button_states(
             &button_states,
+            key_count,
         )));
     }
 
-    let pressed_index: usize = device_to_opendeck(input as usize);
-    log::info!("Button press: device_index={}, opendeck_index={}, state={}", input, pressed_index, state);
+    // `device_to_opendeck_index` is 0-based, while `button_states` has a leading header byte, so add 1
+    match device_to_opendeck_index(input, index_base, inverse_key_map) {
+        Some(pressed_index) => {
+            log::trace!(
+                "Button press: device_index={}, opendeck_index={}, state={}",
+                input,
+                pressed_index,
+                state
+            );
 
-    // `device_to_opendeck` is 0-based, so add 1
-    // I'll probably have to refactor all of this off-by-one stuff in this file, but that's a future me problem
-    if pressed_index < KEY_COUNT {
-        button_states[pressed_index + 1] = state;
-    } else {
-        log::warn!("Button index {} out of range (max: {})", pressed_index, KEY_COUNT - 1);
+            button_states[pressed_index as usize + 1] = state;
+        }
+        None => {
+            log::warn!("Button index {} out of range (max: {})", input, key_count);
+        }
     }
 
     Ok(DeviceInput::ButtonStateChange(read_button_states(
         &button_states,
+        key_count,
     )))
 }
+
+fn read_encoder_value(input: u8, layout: Layout) -> Result<DeviceInput, MirajazzError> {
+    let mut encoder_values = vec![0i8; layout.encoders];
+
+    let Some(value) = encoder_values.first_mut() else {
+        return Err(MirajazzError::BadData);
+    };
+
+    *value = match input {
+        ENCODER_CCW_INPUT => -1,
+        ENCODER_CW_INPUT => 1,
+        _ => unreachable!(),
+    };
+
+    Ok(DeviceInput::EncoderTwist(encoder_values))
+}
+
+fn read_encoder_press(state: u8, layout: Layout) -> Result<DeviceInput, MirajazzError> {
+    let mut encoder_states = vec![false; layout.encoders];
+
+    let Some(pressed) = encoder_states.first_mut() else {
+        return Err(MirajazzError::BadData);
+    };
+
+    *pressed = state != 0;
+
+    Ok(DeviceInput::EncoderStateChange(encoder_states))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::KINDS;
+
+    /// Returns OpenDeck positions reported as pressed by button input
+    fn pressed(input: Result<DeviceInput, MirajazzError>) -> Vec<usize> {
+        match input {
+            Ok(DeviceInput::ButtonStateChange(buttons)) => buttons
+                .iter()
+                .enumerate()
+                .filter_map(|(index, pressed)| pressed.then_some(index))
+                .collect(),
+            other => panic!("Expected button input, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_kind_processes_its_keys() {
+        for kind in KINDS {
+            // Input 11 is OpenDeck 0 with the default key map
+            assert_eq!(
+                pressed(kind.input_processor()(11, 1)),
+                vec![0],
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn zero_based_firmware_reports_zero_as_first_key() {
+        let identity: Vec<u8> = (0..AMPGD6_LAYOUT.key_count() as u8).collect();
+        let process = |input| process_input_with(input, 1, AMPGD6_LAYOUT, 0, &identity);
+
+        assert_eq!(pressed(process(0)), vec![0]);
+        assert_eq!(pressed(process(14)), vec![14]);
+        assert!(matches!(process(15), Err(MirajazzError::BadData)));
+    }
+
+    /// Keypad other than the D6 one, to check nothing falls back to [AMPGD6_LAYOUT]
+    const LAYOUT_4X4: Layout = Layout {
+        rows: 4,
+        cols: 4,
+        encoders: 1,
+    };
+
+    #[test]
+    fn inverts_key_map_of_other_layouts() {
+        let map: Vec<u8> = (0..16).rev().collect();
+        let inverse = invert_key_map(&map, LAYOUT_4X4);
+
+        assert_eq!(inverse.len(), 16);
+        for (opendeck_index, &device_index) in map.iter().enumerate() {
+            assert_eq!(inverse[device_index as usize] as usize, opendeck_index);
+        }
+    }
+
+    #[test]
+    fn processes_keys_of_other_layouts() {
+        let inverse = invert_key_map(&(0..16).rev().collect::<Vec<u8>>(), LAYOUT_4X4);
+        let process = |input| process_input_with(input, 1, LAYOUT_4X4, 1, &inverse);
+
+        // Every key of the layout is reported, including the ones past the D6 keypad
+        match process(16) {
+            Ok(DeviceInput::ButtonStateChange(buttons)) => assert_eq!(buttons.len(), 16),
+            other => panic!("Expected button input, got {:?}", other),
+        }
+        assert_eq!(pressed(process(16)), vec![0]);
+        assert_eq!(pressed(process(1)), vec![15]);
+        assert!(pressed(process(0)).is_empty());
+        assert!(matches!(process(17), Err(MirajazzError::BadData)));
+    }
+
+    #[test]
+    fn keys_outside_of_layout_are_not_mapped() {
+        assert_eq!(opendeck_to_device(LAYOUT_4X4, 16), 16);
+        assert_eq!(opendeck_to_device(AMPGD6_LAYOUT, 15), 15);
+        assert_eq!(opendeck_to_device(AMPGD6_LAYOUT, 0), 10);
+    }
+
+    #[test]
+    fn firmware_inputs_trigger_actions_of_their_images() {
+        // (input the firmware reports, OpenDeck action it triggers) with the default key map
+        let table = [
+            (1, 10),
+            (2, 11),
+            (3, 12),
+            (4, 13),
+            (5, 14),
+            (6, 5),
+            (7, 6),
+            (8, 7),
+            (9, 8),
+            (10, 9),
+            (11, 0),
+            (12, 1),
+            (13, 2),
+            (14, 3),
+            (15, 4),
+        ];
+
+        for (input, action) in table {
+            assert_eq!(pressed(process_input(input, 1)), vec![action], "{}", input);
+            // Image of the action goes to the slot the firmware reports
+            assert_eq!(opendeck_to_device(AMPGD6_LAYOUT, action as u8) + 1, input);
+        }
+    }
+
+    #[test]
+    fn key_map_round_trips() {
+        for key in 0..AMPGD6_LAYOUT.key_count() as u8 {
+            let device_key = opendeck_to_device(AMPGD6_LAYOUT, key);
+
+            assert_eq!(
+                device_to_opendeck_index(device_key + 1, 1, &INVERSE_KEY_MAP),
+                Some(key)
+            );
+        }
+    }
+
+    #[test]
+    fn index_below_first_key_does_not_underflow() {
+        assert_eq!(device_to_opendeck_index(0, 1, &INVERSE_KEY_MAP), None);
+        assert_eq!(device_to_opendeck_index(0, 0, &INVERSE_KEY_MAP), Some(10));
+    }
+
+    #[test]
+    fn indexes_past_the_keypad_have_no_opendeck_index() {
+        assert_eq!(device_to_opendeck_index(16, 1, &INVERSE_KEY_MAP), None);
+        assert_eq!(device_to_opendeck_index(u8::MAX, 1, &INVERSE_KEY_MAP), None);
+        assert_eq!(device_to_opendeck_index(15, 0, &INVERSE_KEY_MAP), None);
+
+        // Slots left unfilled by a broken map aren't keys either
+        let partial = [1, 0, u8::MAX];
+        assert_eq!(device_to_opendeck_index(1, 0, &partial), Some(0));
+        assert_eq!(device_to_opendeck_index(2, 0, &partial), None);
+    }
+
+    #[test]
+    fn zero_reports_every_key_released() {
+        match process_input(0, 1) {
+            Ok(DeviceInput::ButtonStateChange(buttons)) => {
+                assert_eq!(buttons.len(), AMPGD6_LAYOUT.key_count());
+                assert!(buttons.iter().all(|pressed| !pressed));
+            }
+            other => panic!("Expected button input, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn key_input_reports_only_its_button() {
+        // With the default key map input 11 is OpenDeck 0 and input 6 OpenDeck 5
+        let mut expected = vec![false; AMPGD6_LAYOUT.key_count()];
+        expected[0] = true;
+        match process_input(11, 1) {
+            Ok(DeviceInput::ButtonStateChange(buttons)) => assert_eq!(buttons, expected),
+            other => panic!("Expected button input, got {:?}", other),
+        }
+
+        assert_eq!(pressed(process_input(6, 1)), vec![5]);
+    }
+
+    #[test]
+    fn released_key_reports_every_key_released() {
+        assert!(pressed(process_input(11, 0)).is_empty());
+    }
+
+    #[test]
+    fn every_input_is_handled() {
+        for input in 0..=u8::MAX {
+            for state in 0..=u8::MAX {
+                let result = std::panic::catch_unwind(|| process_input(input, state))
+                    .unwrap_or_else(|_| panic!("Input {}, {} panicked", input, state));
+
+                let accepted = matches!(
+                    input,
+                    0..=15 | ENCODER_CCW_INPUT | ENCODER_CW_INPUT | ENCODER_PRESS_INPUT
+                );
+                match result {
+                    Ok(_) => assert!(accepted, "Input {}, {} was accepted", input, state),
+                    Err(MirajazzError::BadData) => {
+                        assert!(!accepted, "Input {}, {} was refused", input, state)
+                    }
+                    Err(err) => panic!("Input {}, {} failed with {}", input, state, err),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_state_buffer_reads_missing_keys_as_released() {
+        assert_eq!(read_button_states(&[], 15), vec![false; 15]);
+        assert_eq!(read_button_states(&[0], 15), vec![false; 15]);
+
+        let mut expected = vec![false; 15];
+        expected[1] = true;
+        assert_eq!(read_button_states(&[0, 0, 1], 15), expected);
+    }
+
+    #[test]
+    fn first_and_last_inputs_are_keys() {
+        // With the default key map device key 1 shows OpenDeck 10 and device key 15 OpenDeck 4
+        assert_eq!(pressed(process_input(1, 1)), vec![10]);
+        assert_eq!(pressed(process_input(15, 1)), vec![4]);
+    }
+
+    #[test]
+    fn dial_inputs_are_encoder_events() {
+        assert!(matches!(
+            process_input(ENCODER_CW_INPUT, 1),
+            Ok(DeviceInput::EncoderTwist(values)) if values == [1]
+        ));
+        assert!(matches!(
+            process_input(ENCODER_CCW_INPUT, 1),
+            Ok(DeviceInput::EncoderTwist(values)) if values == [-1]
+        ));
+        assert!(matches!(
+            process_input(ENCODER_PRESS_INPUT, 1),
+            Ok(DeviceInput::EncoderStateChange(states)) if states == [true]
+        ));
+        assert!(matches!(
+            process_input(ENCODER_PRESS_INPUT, 0),
+            Ok(DeviceInput::EncoderStateChange(states)) if states == [false]
+        ));
+    }
+
+    #[test]
+    fn dial_inputs_are_refused_without_encoder() {
+        let layout = Layout {
+            encoders: 0,
+            ..AMPGD6_LAYOUT
+        };
+        let process = |input| process_input_with(input, 1, layout, 1, &INVERSE_KEY_MAP);
+
+        for input in [ENCODER_CCW_INPUT, ENCODER_CW_INPUT, ENCODER_PRESS_INPUT] {
+            assert!(matches!(process(input), Err(MirajazzError::BadData)));
+        }
+        assert_eq!(pressed(process(11)), vec![0]);
+    }
+
+    #[test]
+    fn input_past_last_key_is_refused() {
+        assert!(matches!(process_input(16, 1), Err(MirajazzError::BadData)));
+    }
+}
@@ -0,0 +1,93 @@
+//! OpenDeck plugin for the FIFINE Ampligame D6, usable as a library to drive the devices
+//! from other applications, see [plugin::Plugin]
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, LazyLock, OnceLock},
+};
+
+use config::Config;
+use device::{DeviceEventCallback, DeviceHandle, INPUT_EVENTS_CAPACITY, KeptImages, QueuedImage};
+use images::FrameCache;
+use mirajazz::state::DeviceStateUpdate;
+use tokio::sync::{Mutex, RwLock, broadcast};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+pub mod config;
+pub mod device;
+pub mod error;
+pub mod images;
+pub mod inputs;
+pub mod logging;
+pub mod mappings;
+pub mod metrics;
+pub mod plugin;
+pub mod simulated;
+pub mod watcher;
+
+// Config given with `plugin::Plugin::with_config`, filled from the config file instead once
+// CONFIG is first read, so a config given after that is refused. Unit tests get the defaults
+// instead of the config file of whoever runs them
+pub static CONFIG_OVERRIDE: OnceLock<Config> = OnceLock::new();
+pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
+    #[cfg(test)]
+    let initial = Config::default;
+    #[cfg(not(test))]
+    let initial = Config::load;
+
+    CONFIG_OVERRIDE.get_or_init(initial).clone()
+});
+// Connected devices, handles are taken out with `device::get_device` so the lock is never held
+// across device I/O. NAMES is the only lock taken before it, no other lock is taken while
+// holding it
+pub static DEVICES: LazyLock<RwLock<HashMap<String, Arc<DeviceHandle>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+pub static TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+// Token of the discovery task, or the simulated device, started by `plugin::Plugin::run`
+pub static WATCHER_TOKEN: LazyLock<Mutex<Option<CancellationToken>>> =
+    LazyLock::new(|| Mutex::new(None));
+// Tokens of the current connection for each device, children of the ones in `TOKENS`
+pub static CONNECTION_TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+// Last brightness set from OpenDeck for each device, re-applied when it reconnects
+pub static BRIGHTNESS: LazyLock<RwLock<HashMap<String, u8>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+// Devices paused with `device::set_device_enabled`, kept while they reconnect
+pub static DISABLED: LazyLock<RwLock<HashSet<String>>> =
+    LazyLock::new(|| RwLock::new(HashSet::new()));
+// Names devices are registered with in OpenDeck, kept while they reconnect, see `device::register_device`
+pub static NAMES: LazyLock<RwLock<HashMap<String, String>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+// Pressed state of every button of each device, by OpenDeck index, see `device::button_snapshot`
+pub static BUTTON_STATES: LazyLock<RwLock<HashMap<String, Vec<bool>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+// Devices with a debounced flush scheduled, see `device::flush_debounced`
+pub static PENDING_FLUSHES: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+// Images of devices that lost their connection or were removed, put back once the device
+// connects again, see `device::restore_images`
+pub static RESTORE_IMAGES: LazyLock<Mutex<HashMap<String, KeptImages>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Animation of each (device id, OpenDeck position) playing a multi-frame image, see `device::start_animation`
+pub static ANIMATIONS: LazyLock<Mutex<HashMap<(String, u8), CancellationToken>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Encoded frames of the animations shown last, see `images::FrameCache`
+pub static FRAME_CACHE: LazyLock<Mutex<FrameCache>> =
+    LazyLock::new(|| Mutex::new(FrameCache::new(CONFIG.animation_cache_size)));
+// Images OpenDeck sent for each (device id, OpenDeck position) while it played an animation,
+// shown once the animation played to its end, see `config::AnimationUpdates::Queue`
+pub static QUEUED_IMAGES: LazyLock<Mutex<HashMap<(String, u8), QueuedImage>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Debug overlay task of each device id, see `device::set_debug_overlay`
+#[cfg(feature = "text")]
+pub static DEBUG_OVERLAYS: LazyLock<Mutex<HashMap<String, CancellationToken>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+// Callbacks registered with `device::on_device_event`, a plain lock as they're registered
+// from synchronous code
+pub static DEVICE_EVENT_CALLBACKS: LazyLock<std::sync::RwLock<Vec<DeviceEventCallback>>> =
+    LazyLock::new(|| std::sync::RwLock::new(Vec::new()));
+// Input of every device as it's forwarded to OpenDeck, see `device::subscribe_events`
+pub static INPUT_EVENTS: LazyLock<broadcast::Sender<(String, DeviceStateUpdate)>> =
+    LazyLock::new(|| broadcast::channel(INPUT_EVENTS_CAPACITY).0);
+pub static TRACKER: LazyLock<Mutex<TaskTracker>> = LazyLock::new(|| Mutex::new(TaskTracker::new()));
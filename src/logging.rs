@@ -0,0 +1,72 @@
+use simplelog::{
+    ColorChoice, CombinedLogger, ConfigBuilder, LevelFilter, SharedLogger, TermLogger, TerminalMode,
+};
+
+/// Log filter, as comma-separated entries of either a default level or `target=level`,
+/// e.g. `info,opendeck_ampgd6::device=trace`
+pub const LOG_ENV: &str = "OPENDECK_AMPGD6_LOG";
+
+/// Initializes logging to stdout, with levels from [LOG_ENV] and `info` by default
+pub fn init_logger() {
+    let (default_level, targets, invalid) = match std::env::var(LOG_ENV) {
+        Ok(value) => parse_filter(&value),
+        Err(_) => (LevelFilter::Info, vec![], vec![]),
+    };
+
+    // Targets with their own level are logged only by their own logger
+    let mut default_config = ConfigBuilder::new();
+    for (target, _) in &targets {
+        default_config.add_filter_ignore(target.clone());
+    }
+
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        default_level,
+        default_config.build(),
+        TerminalMode::Stdout,
+        ColorChoice::Never,
+    )];
+
+    for (target, level) in &targets {
+        loggers.push(TermLogger::new(
+            *level,
+            ConfigBuilder::new()
+                .add_filter_allow(target.clone())
+                .build(),
+            TerminalMode::Stdout,
+            ColorChoice::Never,
+        ));
+    }
+
+    CombinedLogger::init(loggers).unwrap();
+
+    for entry in invalid {
+        log::warn!(
+            "Ignoring invalid entry in {}: {:?}, expected level or target=level",
+            LOG_ENV,
+            entry
+        );
+    }
+}
+
+/// Splits filter into the default level, per-target levels and entries that couldn't be parsed
+fn parse_filter(value: &str) -> (LevelFilter, Vec<(String, LevelFilter)>, Vec<String>) {
+    let mut default_level = LevelFilter::Info;
+    let mut targets = vec![];
+    let mut invalid = vec![];
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once('=') {
+            Some((target, level)) if !target.trim().is_empty() => match level.trim().parse() {
+                Ok(level) => targets.push((target.trim().to_string(), level)),
+                Err(_) => invalid.push(entry.to_string()),
+            },
+            Some(_) => invalid.push(entry.to_string()),
+            None => match entry.parse() {
+                Ok(level) => default_level = level,
+                Err(_) => invalid.push(entry.to_string()),
+            },
+        }
+    }
+
+    (default_level, targets, invalid)
+}
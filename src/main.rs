@@ -1,40 +1,30 @@
-use device::{handle_error, handle_set_image};
-use mirajazz::device::Device;
 use openaction::*;
-use std::{collections::HashMap, process::exit, sync::LazyLock};
-use tokio::sync::{Mutex, RwLock};
-use tokio_util::{sync::CancellationToken, task::TaskTracker};
-use watcher::watcher_task;
+use opendeck_ampgd6::{
+    TRACKER,
+    config::Config,
+    device::{
+        get_device, handle_error_with_outbound, handle_set_image, set_device_brightness,
+        shutdown_all,
+    },
+    logging, mappings, metrics,
+    plugin::Plugin,
+    watcher,
+};
+use std::process::exit;
 
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{SignalKind, signal};
 
-mod device;
-mod inputs;
-mod mappings;
-mod watcher;
-
-pub static DEVICES: LazyLock<RwLock<HashMap<String, Device>>> =
-    LazyLock::new(|| RwLock::new(HashMap::new()));
-pub static TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
-    LazyLock::new(|| RwLock::new(HashMap::new()));
-pub static TRACKER: LazyLock<Mutex<TaskTracker>> = LazyLock::new(|| Mutex::new(TaskTracker::new()));
-
-struct GlobalEventHandler {}
+struct GlobalEventHandler {
+    config: Config,
+}
 impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn plugin_ready(
         &self,
         _outbound: &mut openaction::OutboundEventManager,
     ) -> EventHandlerResult {
-        let tracker = TRACKER.lock().await.clone();
-
-        let token = CancellationToken::new();
-        tracker.spawn(watcher_task(token.clone()));
-
-        TOKENS
-            .write()
-            .await
-            .insert("_watcher_task".to_string(), token);
+        // Stopped through `WATCHER_TOKEN` by `shutdown_all` in `main`
+        Plugin::new().with_config(self.config.clone()).run().await;
 
         log::info!("Plugin initialized");
 
@@ -44,7 +34,7 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn set_image(
         &self,
         event: SetImageEvent,
-        _outbound: &mut OutboundEventManager,
+        outbound: &mut OutboundEventManager,
     ) -> EventHandlerResult {
         log::debug!("Asked to set image: {:#?}", event);
 
@@ -56,13 +46,17 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
 
         let id = event.device.clone();
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            handle_set_image(device, event)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
-        } else {
-            log::error!("Received event for unknown device: {}", event.device);
+        let result = match get_device(&id).await {
+            Some(device) => handle_set_image(&device, event).await,
+            None => {
+                log::error!("Received event for unknown device: {}", id);
+
+                return Ok(());
+            }
+        };
+
+        if let Err(err) = result {
+            handle_error_with_outbound(&id, err, outbound).await;
         }
 
         Ok(())
@@ -71,20 +65,12 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn set_brightness(
         &self,
         event: SetBrightnessEvent,
-        _outbound: &mut OutboundEventManager,
+        outbound: &mut OutboundEventManager,
     ) -> EventHandlerResult {
         log::debug!("Asked to set brightness: {:#?}", event);
 
-        let id = event.device.clone();
-
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            device
-                .set_brightness(event.brightness)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
-        } else {
-            log::error!("Received event for unknown device: {}", event.device);
+        if let Err(err) = set_device_brightness(&event.device, event.brightness).await {
+            handle_error_with_outbound(&event.device, err, outbound).await;
         }
 
         Ok(())
@@ -94,16 +80,8 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
 struct ActionEventHandler {}
 impl openaction::ActionEventHandler for ActionEventHandler {}
 
-async fn shutdown() {
-    let tokens = TOKENS.write().await;
-
-    for (_, token) in tokens.iter() {
-        token.cancel();
-    }
-}
-
-async fn connect() {
-    if let Err(error) = init_plugin(GlobalEventHandler {}, ActionEventHandler {}).await {
+async fn connect(config: Config) {
+    if let Err(error) = init_plugin(GlobalEventHandler { config }, ActionEventHandler {}).await {
         log::error!("Failed to initialize plugin: {}", error);
 
         exit(1);
@@ -130,22 +108,30 @@ async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    simplelog::TermLogger::init(
-        simplelog::LevelFilter::Info,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Stdout,
-        simplelog::ColorChoice::Never,
-    )
-    .unwrap();
+    logging::init_logger();
+
+    if watcher::list_requested() {
+        watcher::list_candidates().await?;
+
+        return Ok(());
+    }
+
+    // Load config early, so problems with it are logged on startup, and hand it to the plugin
+    // like an embedding application would
+    let config = Config::load();
+
+    if let Err(err) = mappings::validate_manifest(mappings::MANIFEST) {
+        log::error!("{}", err);
+    }
 
     tokio::select! {
-        _ = connect() => {},
+        _ = connect(config) => {},
         _ = sigterm() => {},
     }
 
     log::info!("Shutting down");
 
-    shutdown().await;
+    shutdown_all().await;
 
     let tracker = TRACKER.lock().await.clone();
 
@@ -154,6 +140,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracker.close();
     tracker.wait().await;
 
+    log::info!("Metrics: {}", metrics::snapshot_metrics());
+
     log::info!("Tasks are finished, exiting now");
 
     Ok(())
@@ -1,5 +1,18 @@
+use std::sync::LazyLock;
+
+use async_hid::DeviceId;
+use serde::Deserialize;
+
+use crate::{
+    CONFIG,
+    config::Config,
+    device::{DeviceIo, run_init_commands},
+    inputs::{InputProcessor, process_input, process_v2_input},
+};
+
 use mirajazz::{
     device::DeviceQuery,
+    error::MirajazzError,
     types::{HidDeviceInfo, ImageFormat, ImageMirroring, ImageMode, ImageRotation},
 };
 
@@ -7,59 +20,378 @@ use mirajazz::{
 // Previously used "99" from the source project akp153, now changed to "d6" for this plugin
 pub const DEVICE_NAMESPACE: &str = "d6";
 
+/// Manifest bundled with the plugin
+pub const MANIFEST: &str = include_str!("../manifest.json");
+
+/// Fields of `manifest.json` that have to agree with the constants here
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(rename = "DeviceNamespace")]
+    device_namespace: String,
+}
+
+/// Checks that the manifest matches [DEVICE_NAMESPACE], otherwise OpenDeck ignores the devices we register
+///
+/// The manifest doesn't describe the layout, so [ROW_COUNT] and [COL_COUNT] can't be checked against it
+pub fn validate_manifest(manifest: &str) -> Result<(), String> {
+    let manifest: Manifest = serde_json::from_str(manifest)
+        .map_err(|err| format!("Failed to parse manifest: {}", err))?;
+
+    if manifest.device_namespace != DEVICE_NAMESPACE {
+        return Err(format!(
+            "DeviceNamespace in manifest.json is {:?}, but devices are registered with {:?}",
+            manifest.device_namespace, DEVICE_NAMESPACE
+        ));
+    }
+
+    Ok(())
+}
+
 pub const ROW_COUNT: usize = 3;
 pub const COL_COUNT: usize = 5;
 pub const KEY_COUNT: usize = ROW_COUNT * COL_COUNT;
-pub const ENCODER_COUNT: usize = 0;
+pub const ENCODER_COUNT: usize = 1;
 
-#[derive(Debug, Clone)]
+/// Grid of keys and number of encoders of a device, see [Kind::layout]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub rows: usize,
+    pub cols: usize,
+    pub encoders: usize,
+}
+
+impl Layout {
+    pub const fn key_count(&self) -> usize {
+        self.rows * self.cols
+    }
+}
+
+/// Layout of the Ampligame D6, which the key map and the input codes are written for
+pub const AMPGD6_LAYOUT: Layout = Layout {
+    rows: ROW_COUNT,
+    cols: COL_COUNT,
+    encoders: ENCODER_COUNT,
+};
+
+/// Shape of a device for host code adapting to it, see [Kind::capabilities]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub key_count: usize,
+    pub encoder_count: usize,
+    pub row_count: usize,
+    pub col_count: usize,
+    /// Size of the screen of every key in pixels, zero if the keys have no screens
+    pub screen_size: (u16, u16),
+}
+
+impl Capabilities {
+    pub const fn has_screen(&self) -> bool {
+        self.screen_size.0 > 0 && self.screen_size.1 > 0
+    }
+
+    pub const fn has_encoder(&self) -> bool {
+        self.encoder_count > 0
+    }
+}
+
+/// OpenDeck to device key index mapping, `KEY_MAP[opendeck_index] = device_index`
+///
+/// Default for `key_map` in the config, the ss550-like layout with rows in reverse order
+pub const KEY_MAP: [u8; KEY_COUNT] = [10, 11, 12, 13, 14, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4];
+
+// Input codes the firmware reports for the volume dial, same as on other Mirabox-based devices
+pub const ENCODER_CCW_INPUT: u8 = 0x90;
+pub const ENCODER_CW_INPUT: u8 = 0x91;
+pub const ENCODER_PRESS_INPUT: u8 = 0x33;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Kind {
     AMPGD6,
+    AMPGD6V2,
 }
 
 pub const FIFINE_VID: u16 = 0x3142;
 pub const AMPGD6_PID: u16 = 0x0007;
+pub const AMPGD6V2_PID: u16 = 0x0008;
 
-// Map all queries to usage page 65440 and usage id 1 for now
-pub const AMPGD6_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, FIFINE_VID, AMPGD6_PID);
+// Vendor-defined usage page and usage id of the HID interface the firmware takes commands on
+pub const AMPGD6_USAGE_PAGE: u16 = 0xffa0;
+pub const AMPGD6_USAGE_ID: u16 = 1;
 
-pub const QUERIES: [DeviceQuery; 1] = [
-    AMPGD6_QUERY,
-];
+pub const AMPGD6_QUERY: DeviceQuery = Kind::AMPGD6.query();
+pub const AMPGD6V2_QUERY: DeviceQuery = Kind::AMPGD6V2.query();
 
-/// Returns correct image format for device kind and key
-pub fn get_image_format_for_key(kind: &Kind, _key: u8) -> ImageFormat {
-    // AMPGD6 doesn't need rotation or mirroring - images are displayed normally
-    let size = if kind.protocol_version() == 1 {
-        (105, 105)
+pub const KINDS: [Kind; 2] = [Kind::AMPGD6, Kind::AMPGD6V2];
+pub const BUILTIN_QUERIES: [DeviceQuery; 2] = [AMPGD6_QUERY, AMPGD6V2_QUERY];
+
+/// User-supplied query, kept separately because [DeviceQuery] doesn't expose its fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuerySpec {
+    pub usage_page: u16,
+    pub usage_id: u16,
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl QuerySpec {
+    /// Parses a single `usage_page:usage_id:vid:pid` tuple, all values in hex with optional `0x` prefix
+    pub fn parse(entry: &str) -> Option<Self> {
+        let values = parse_hex_values(entry)?;
+
+        match values[..] {
+            [usage_page, usage_id, vid, pid] => Some(Self {
+                usage_page,
+                usage_id,
+                vid,
+                pid,
+            }),
+            _ => None,
+        }
+    }
+
+    pub const fn to_query(&self) -> DeviceQuery {
+        DeviceQuery::new(self.usage_page, self.usage_id, self.vid, self.pid)
+    }
+}
+
+/// Parses `:`-separated values in hex with optional `0x` prefix
+fn parse_hex_values(entry: &str) -> Option<Vec<u16>> {
+    entry
+        .split(':')
+        .map(|v| {
+            let v = v.trim();
+            u16::from_str_radix(v.strip_prefix("0x").unwrap_or(v), 16).ok()
+        })
+        .collect()
+}
+
+/// Returns usage page and usage id of a `usage_page:usage_id` pair in hex with optional
+/// `0x` prefix
+pub fn parse_usage(value: &str) -> Option<(u16, u16)> {
+    match parse_hex_values(value)?[..] {
+        [usage_page, usage_id] => Some((usage_page, usage_id)),
+        _ => None,
+    }
+}
+
+/// Usage page and usage id from [crate::config::Config::usage]
+pub static USAGE_OVERRIDE: LazyLock<Option<(u16, u16)>> = LazyLock::new(|| CONFIG.usage_override());
+
+/// Extra queries from [crate::config::Config::extra_queries]
+pub static EXTRA_QUERIES: LazyLock<Vec<QuerySpec>> = LazyLock::new(|| CONFIG.extra_queries());
+
+/// Queries used for probing devices: the built-in ones merged with the extra ones
+pub static QUERIES: LazyLock<Vec<DeviceQuery>> = LazyLock::new(|| {
+    let skip_builtin = CONFIG.skip_builtin_queries;
+
+    let mut queries = vec![];
+
+    if skip_builtin && !EXTRA_QUERIES.is_empty() {
+        log::info!("Skipping built-in device queries");
     } else {
-        (105, 105)
-    };
+        if skip_builtin {
+            log::warn!(
+                "Built-in queries are skipped, but there are no extra queries, keeping them"
+            );
+        }
+
+        if let Some((usage_page, usage_id)) = *USAGE_OVERRIDE {
+            log::info!(
+                "Probing built-in devices on usage page {:04x} and usage id {:04x}",
+                usage_page,
+                usage_id
+            );
+
+            queries.extend(
+                KINDS.map(|kind| DeviceQuery::new(usage_page, usage_id, FIFINE_VID, kind.pid())),
+            );
+        } else {
+            queries.extend(BUILTIN_QUERIES);
+        }
+    }
+
+    for spec in EXTRA_QUERIES.iter() {
+        log::info!("Probing extra query {:04x?}", spec);
+        queries.push(spec.to_query());
+    }
+
+    queries
+});
+
+/// Returns platform-specific HID path of the device
+///
+/// How long the path stays the same depends on the platform: `/dev/hidrawN` on Linux may change
+/// when the device is replugged or the system reboots, the UNC path on Windows is tied to the
+/// USB port, and the registry entry id on macOS changes every time the device reconnects
+pub fn hid_path(dev: &HidDeviceInfo) -> String {
+    match &dev.id {
+        #[cfg(target_os = "linux")]
+        DeviceId::DevPath(path) => path.display().to_string(),
+        #[cfg(target_os = "windows")]
+        DeviceId::UncPath(path) => path.to_string(),
+        #[cfg(target_os = "macos")]
+        DeviceId::RegistryEntryId(id) => id.to_string(),
+        #[allow(unreachable_patterns)]
+        id => format!("{:?}", id),
+    }
+}
+
+/// Returns correct image format for device kind and key
+///
+/// Image mode, rotation and mirroring from the config take precedence over [Kind::image_mode],
+/// [Kind::rotation] and [Kind::mirror]
+pub fn get_image_format_for_key(kind: &Kind, key: u8) -> ImageFormat {
+    image_format_with(kind, key, &CONFIG)
+}
+
+/// Same as [get_image_format_for_key], with overrides from the given config
+pub fn image_format_with(kind: &Kind, key: u8, config: &Config) -> ImageFormat {
+    let (width, height) = kind.key_image_size(key);
 
     ImageFormat {
-        mode: ImageMode::JPEG,
-        size,
-        rotation: ImageRotation::Rot180, // AMPGD6 needs 180° rotation
-        mirror: ImageMirroring::None,  // No mirroring needed for AMPGD6
+        mode: config.image_mode().unwrap_or_else(|| kind.image_mode()),
+        size: (width as usize, height as usize),
+        rotation: config.image_rotation().unwrap_or_else(|| kind.rotation()),
+        mirror: config.image_mirroring().unwrap_or_else(|| kind.mirror()),
     }
 }
 
 impl Kind {
     /// Matches devices VID+PID pairs to correct kinds
+    ///
+    /// Pairs from [EXTRA_QUERIES] are treated as AMPGD6 with a different firmware revision
     pub fn from_vid_pid(vid: u16, pid: u16) -> Option<Self> {
         match vid {
             FIFINE_VID => match pid {
                 AMPGD6_PID => Some(Kind::AMPGD6),
+                AMPGD6V2_PID => Some(Kind::AMPGD6V2),
                 _ => None,
             },
             _ => None,
         }
+        .or_else(|| {
+            EXTRA_QUERIES
+                .iter()
+                .any(|spec| spec.vid == vid && spec.pid == pid)
+                .then_some(Kind::AMPGD6)
+        })
+    }
+
+    /// Returns product id of the device, all the kinds share [FIFINE_VID]
+    pub const fn pid(&self) -> u16 {
+        match self {
+            Self::AMPGD6 => AMPGD6_PID,
+            Self::AMPGD6V2 => AMPGD6V2_PID,
+        }
+    }
+
+    /// Returns usage page of the HID interface the device takes commands on
+    pub const fn usage_page(&self) -> u16 {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => AMPGD6_USAGE_PAGE,
+        }
+    }
+
+    /// Returns usage id of the HID interface the device takes commands on
+    pub const fn usage_id(&self) -> u16 {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => AMPGD6_USAGE_ID,
+        }
+    }
+
+    /// Returns query probing for the device, see [USAGE_OVERRIDE] to probe another interface
+    pub const fn query(&self) -> DeviceQuery {
+        DeviceQuery::new(self.usage_page(), self.usage_id(), FIFINE_VID, self.pid())
+    }
+
+    /// Returns grid of keys and number of encoders of the device
+    pub fn layout(&self) -> Layout {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => AMPGD6_LAYOUT,
+        }
+    }
+
+    /// Returns number of keys and encoders, grid and screen size of the device
+    pub fn capabilities(&self) -> Capabilities {
+        let layout = self.layout();
+
+        Capabilities {
+            key_count: layout.key_count(),
+            encoder_count: layout.encoders,
+            row_count: layout.rows,
+            col_count: layout.cols,
+            screen_size: self.key_image_size(0),
+        }
+    }
+
+    /// Returns index the firmware reports for the first key, the D6 counts keys from 1
+    /// and uses 0 to report that every key is released
+    pub const fn index_base(&self) -> u8 {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => 1,
+        }
+    }
+
+    /// Returns function the reader of the device turns raw firmware input with, which knows
+    /// the layout and [Kind::index_base] of the kind
+    pub fn input_processor(&self) -> InputProcessor {
+        match self {
+            Self::AMPGD6 => process_input,
+            Self::AMPGD6V2 => process_v2_input,
+        }
+    }
+
+    /// Runs the commands that get the device going after connecting, returning number
+    /// of the ones that failed, see [run_init_commands]
+    ///
+    /// Both revisions take the same brightness, clear and flush sequence, a firmware that
+    /// needs other wake-up commands gets an arm of its own
+    pub async fn init_sequence<D: DeviceIo>(
+        &self,
+        device: &D,
+        brightness: u8,
+    ) -> Result<usize, MirajazzError> {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => run_init_commands(device, brightness).await,
+        }
     }
 
     /// Returns protocol version for device
     pub fn protocol_version(&self) -> usize {
         match self {
             Self::AMPGD6 => 1, // Back to version 1 - the error might be related to button count or initialization
+            Self::AMPGD6V2 => 2,
+        }
+    }
+
+    /// Returns image size of the key at OpenDeck position, the same for every key
+    ///
+    /// The size was measured on the first revision, the v2 controller is assumed to have the
+    /// same screens until one is measured, so it doesn't depend on the protocol version
+    pub fn key_image_size(&self, _key: u8) -> (u16, u16) {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => (105, 105),
+        }
+    }
+
+    /// Returns default encoding of button images
+    pub fn image_mode(&self) -> ImageMode {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => ImageMode::JPEG,
+        }
+    }
+
+    /// Returns default rotation of button images, the screens are mounted upside down
+    pub fn rotation(&self) -> ImageRotation {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => ImageRotation::Rot180,
+        }
+    }
+
+    /// Returns default mirroring of button images
+    pub fn mirror(&self) -> ImageMirroring {
+        match self {
+            Self::AMPGD6 | Self::AMPGD6V2 => ImageMirroring::None,
         }
     }
 
@@ -68,6 +400,7 @@ impl Kind {
     pub fn human_name(&self) -> String {
         match &self {
             Self::AMPGD6 => "FIFINE Ampligame D6",
+            Self::AMPGD6V2 => "FIFINE Ampligame D6 (v2)",
         }
         .to_string()
     }
@@ -77,6 +410,7 @@ impl Kind {
     pub fn id_suffix(&self) -> String {
         match &self {
             Self::AMPGD6 => "AMPGD6",
+            Self::AMPGD6V2 => "AMPGD6V2",
         }
         .to_string()
     }
@@ -88,3 +422,196 @@ pub struct CandidateDevice {
     pub dev: HidDeviceInfo,
     pub kind: Kind,
 }
+
+impl CandidateDevice {
+    /// Returns name to register the device with, custom one from
+    /// [crate::config::Config::device_names] if configured, otherwise [Kind::human_name]
+    pub fn display_name(&self) -> String {
+        CONFIG
+            .device_names
+            .get(&hid_path(&self.dev))
+            .cloned()
+            .unwrap_or_else(|| self.kind.human_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pids_resolve_to_their_kinds() {
+        assert_eq!(
+            Kind::from_vid_pid(FIFINE_VID, AMPGD6_PID),
+            Some(Kind::AMPGD6)
+        );
+        assert_eq!(
+            Kind::from_vid_pid(FIFINE_VID, AMPGD6V2_PID),
+            Some(Kind::AMPGD6V2)
+        );
+        assert_eq!(Kind::from_vid_pid(FIFINE_VID, 0x0009), None);
+        assert_eq!(Kind::from_vid_pid(0x1234, AMPGD6_PID), None);
+    }
+
+    #[test]
+    fn kinds_have_distinct_ids_and_protocols() {
+        assert_eq!(Kind::AMPGD6.protocol_version(), 1);
+        assert_eq!(Kind::AMPGD6V2.protocol_version(), 2);
+        assert_ne!(Kind::AMPGD6.id_suffix(), Kind::AMPGD6V2.id_suffix());
+        assert_ne!(Kind::AMPGD6.human_name(), Kind::AMPGD6V2.human_name());
+    }
+
+    #[test]
+    fn images_are_turned_by_the_rotation_of_the_kind() {
+        for kind in KINDS {
+            for key in [0, 14] {
+                let format = image_format_with(&kind, key, &Config::default());
+
+                assert!(matches!(format.rotation, ImageRotation::Rot180));
+                assert_eq!(format.size, (105, 105));
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_override_takes_precedence() {
+        for (degrees, expected) in [
+            (0, ImageRotation::Rot0),
+            (90, ImageRotation::Rot90),
+            (180, ImageRotation::Rot180),
+            (270, ImageRotation::Rot270),
+        ] {
+            let config = Config {
+                rotation: Some(degrees),
+                ..Config::default()
+            };
+            let format = image_format_with(&Kind::AMPGD6, 0, &config);
+
+            assert_eq!(format!("{:?}", format.rotation), format!("{:?}", expected));
+        }
+
+        // Invalid rotations are dropped when the config is loaded
+        let mut config = Config {
+            rotation: Some(45),
+            ..Config::default()
+        };
+        config.validate();
+        assert!(matches!(
+            image_format_with(&Kind::AMPGD6, 0, &config).rotation,
+            ImageRotation::Rot180
+        ));
+    }
+
+    #[test]
+    fn mirroring_override_flows_into_image_format() {
+        assert!(matches!(
+            image_format_with(&Kind::AMPGD6, 0, &Config::default()).mirror,
+            ImageMirroring::None
+        ));
+
+        for (mirror, expected) in [
+            ("none", ImageMirroring::None),
+            ("x", ImageMirroring::X),
+            ("y", ImageMirroring::Y),
+            ("both", ImageMirroring::Both),
+        ] {
+            let config = Config {
+                mirror: Some(mirror.to_string()),
+                ..Config::default()
+            };
+            let format = image_format_with(&Kind::AMPGD6, 0, &config);
+
+            assert_eq!(format!("{:?}", format.mirror), format!("{:?}", expected));
+        }
+    }
+
+    #[test]
+    fn image_mode_override_flows_into_image_format() {
+        assert!(matches!(
+            image_format_with(&Kind::AMPGD6, 0, &Config::default()).mode,
+            ImageMode::JPEG
+        ));
+
+        for (mode, expected) in [("jpeg", ImageMode::JPEG), ("bmp", ImageMode::BMP)] {
+            let config = Config {
+                image_mode: Some(mode.to_string()),
+                ..Config::default()
+            };
+            let format = image_format_with(&Kind::AMPGD6, 0, &config);
+
+            assert_eq!(format!("{:?}", format.mode), format!("{:?}", expected));
+        }
+    }
+
+    #[test]
+    fn d6_reports_its_capabilities() {
+        for kind in KINDS {
+            let capabilities = kind.capabilities();
+
+            assert_eq!(
+                capabilities,
+                Capabilities {
+                    key_count: 15,
+                    encoder_count: 1,
+                    row_count: 3,
+                    col_count: 5,
+                    screen_size: (105, 105),
+                }
+            );
+            assert!(capabilities.has_screen());
+            assert!(capabilities.has_encoder());
+        }
+    }
+
+    #[test]
+    fn image_format_has_size_of_its_key() {
+        for kind in KINDS {
+            for key in 0..kind.layout().key_count() as u8 {
+                let (width, height) = kind.key_image_size(key);
+
+                assert_eq!(
+                    image_format_with(&kind, key, &Config::default()).size,
+                    (width as usize, height as usize)
+                );
+            }
+
+            assert_eq!(kind.key_image_size(0), (105, 105));
+        }
+    }
+
+    #[test]
+    fn bundled_manifest_is_valid() {
+        validate_manifest(MANIFEST).unwrap();
+    }
+
+    #[test]
+    fn mismatching_manifest_is_rejected() {
+        let manifest = r#"{"Name": "Ampligame D6", "DeviceNamespace": "99"}"#;
+
+        let err = validate_manifest(manifest).unwrap_err();
+        assert!(err.contains("\"99\""), "{}", err);
+
+        assert!(validate_manifest(r#"{"Name": "Ampligame D6"}"#).is_err());
+        assert!(validate_manifest("not json").is_err());
+    }
+
+    #[test]
+    fn d6_is_probed_on_its_vendor_interface() {
+        assert_eq!(Kind::AMPGD6.usage_page(), 0xffa0);
+        assert_eq!(Kind::AMPGD6.usage_id(), 1);
+        assert_eq!(
+            format!("{:?}", Kind::AMPGD6.query()),
+            format!("{:?}", DeviceQuery::new(0xffa0, 1, 0x3142, 0x0007))
+        );
+        assert_eq!(
+            format!("{:?}", Kind::AMPGD6V2.query()),
+            format!("{:?}", DeviceQuery::new(0xffa0, 1, 0x3142, 0x0008))
+        );
+    }
+
+    #[test]
+    fn builtin_queries_cover_every_kind() {
+        assert_eq!(BUILTIN_QUERIES.len(), KINDS.len());
+        assert_eq!(QUERIES.len(), KINDS.len());
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub static EVENTS_FORWARDED: AtomicU64 = AtomicU64::new(0);
+pub static EVENTS_DEDUPED: AtomicU64 = AtomicU64::new(0);
+pub static EVENTS_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub static IMAGES_SET: AtomicU64 = AtomicU64::new(0);
+pub static IMAGES_CLEARED: AtomicU64 = AtomicU64::new(0);
+pub static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+pub static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Increments a counter by one
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Values of all the counters at some point in time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsSnapshot {
+    /// Device events sent to OpenDeck
+    pub events_forwarded: u64,
+    /// Device events dropped as duplicates
+    pub events_deduped: u64,
+    /// Device events dropped because OpenDeck didn't keep up with them
+    pub events_dropped: u64,
+    /// Button images sent to devices
+    pub images_set: u64,
+    /// Single button or whole device clears
+    pub images_cleared: u64,
+    /// Reconnection attempts after a device dropped
+    pub reconnects: u64,
+    /// Device errors handled, fatal or not
+    pub errors: u64,
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "events forwarded: {}, events deduped: {}, events dropped: {}, images set: {}, images cleared: {}, reconnects: {}, errors: {}",
+            self.events_forwarded,
+            self.events_deduped,
+            self.events_dropped,
+            self.images_set,
+            self.images_cleared,
+            self.reconnects,
+            self.errors
+        )
+    }
+}
+
+/// Returns current values of all the counters
+pub fn snapshot_metrics() -> MetricsSnapshot {
+    MetricsSnapshot {
+        events_forwarded: EVENTS_FORWARDED.load(Ordering::Relaxed),
+        events_deduped: EVENTS_DEDUPED.load(Ordering::Relaxed),
+        events_dropped: EVENTS_DROPPED.load(Ordering::Relaxed),
+        images_set: IMAGES_SET.load(Ordering::Relaxed),
+        images_cleared: IMAGES_CLEARED.load(Ordering::Relaxed),
+        reconnects: RECONNECTS.load(Ordering::Relaxed),
+        errors: ERRORS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,107 @@
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    CONFIG, CONFIG_OVERRIDE, TRACKER, WATCHER_TOKEN,
+    config::Config,
+    device::shutdown_all,
+    simulated::{simulated_task, simulation_enabled},
+    watcher::watcher_task,
+};
+
+/// Starts device discovery and the device tasks, managing the tokens and the tasks
+/// on its own, for embedding the plugin without knowing about its globals
+///
+/// ```no_run
+/// # async fn example(config: opendeck_ampgd6::config::Config) {
+/// use opendeck_ampgd6::plugin::Plugin;
+///
+/// let plugin = Plugin::new().with_config(config).run().await;
+/// // ...
+/// plugin.stop().await;
+/// # }
+/// ```
+pub struct Plugin {
+    config: Option<Config>,
+    simulate: bool,
+}
+
+impl Default for Plugin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plugin {
+    /// Creates a plugin with the config from the config file, simulated if
+    /// [crate::simulated::SIMULATE_ENV] or [crate::simulated::SIMULATE_FLAG] ask for it
+    pub fn new() -> Self {
+        Self {
+            config: None,
+            simulate: simulation_enabled(),
+        }
+    }
+
+    /// Uses the given config instead of loading the config file, only takes effect if
+    /// nothing read the config before [Plugin::run]
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Runs a simulated device instead of probing hardware
+    pub fn simulated(mut self, simulate: bool) -> Self {
+        self.simulate = simulate;
+        self
+    }
+
+    /// Spawns discovery, or the simulated device, returning a handle to stop everything with
+    pub async fn run(self) -> PluginHandle {
+        if let Some(mut config) = self.config {
+            config.validate();
+
+            if CONFIG_OVERRIDE.set(config).is_err() {
+                log::warn!("Config was already loaded, ignoring the given one");
+            }
+        }
+
+        log::info!(
+            "Starting {} {}, keeping frames of up to {} animations",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            CONFIG.animation_cache_size
+        );
+
+        let tracker = TRACKER.lock().await.clone();
+        let token = CancellationToken::new();
+
+        if self.simulate {
+            log::info!("Simulation mode is enabled, not probing hardware");
+
+            tracker.spawn(simulated_task(token.clone(), &CONFIG));
+        } else {
+            tracker.spawn(watcher_task(token.clone()));
+        }
+
+        *WATCHER_TOKEN.lock().await = Some(token.clone());
+
+        PluginHandle { token }
+    }
+}
+
+/// Handle of a running [Plugin], dropping it leaves the plugin running
+pub struct PluginHandle {
+    token: CancellationToken,
+}
+
+impl PluginHandle {
+    /// Stops discovery and every device task, waiting for the devices to be shut down
+    pub async fn stop(self) {
+        self.token.cancel();
+
+        shutdown_all().await;
+
+        let tracker = TRACKER.lock().await.clone();
+        tracker.close();
+        tracker.wait().await;
+    }
+}
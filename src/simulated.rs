@@ -0,0 +1,625 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::Duration,
+};
+
+use mirajazz::{error::MirajazzError, state::DeviceStateUpdate, types::DeviceInput};
+use tokio::{
+    sync::{Mutex, Notify},
+    time::Interval,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config,
+    device::{
+        DeviceBackend, DeviceHandle, DeviceIo, DeviceReader, device_events_task,
+        initial_brightness, log_init_outcome, register_device, remove_device,
+    },
+    inputs::InputProcessor,
+    mappings::{DEVICE_NAMESPACE, KEY_COUNT, Kind},
+};
+
+/// Environment variable that enables simulation mode when set to `1` or `true`
+pub const SIMULATE_ENV: &str = "OPENDECK_AMPGD6_SIMULATE";
+/// Command line flag that enables simulation mode
+pub const SIMULATE_FLAG: &str = "--simulate";
+
+/// Interval between scripted button presses of the simulated device
+pub const SIMULATED_PRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns true if the plugin should run with a simulated device instead of probing hardware
+pub fn simulation_enabled() -> bool {
+    std::env::args().any(|arg| arg == SIMULATE_FLAG)
+        || std::env::var(SIMULATE_ENV).is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
+/// Call made to a [SimulatedDevice], recorded by scripted devices, see [SimulatedDevice::calls]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceCall {
+    SetBrightness(u8),
+    ClearButtonImage(u8),
+    ClearAllButtonImages,
+    WriteImage(u8, Vec<u8>),
+    Flush,
+    KeepAlive,
+    Shutdown,
+}
+
+/// Input queued for the reader of a scripted [SimulatedDevice]
+enum ScriptedRead {
+    /// Raw input byte and state byte, as the firmware would report them
+    Input(u8, u8),
+    /// Updates returned as they are
+    Updates(Vec<DeviceStateUpdate>),
+    Error(MirajazzError),
+}
+
+/// Failure injected with [SimulatedDevice::fail_next]
+struct Failure {
+    matches: fn(&DeviceCall) -> bool,
+    remaining: usize,
+}
+
+#[derive(Default)]
+struct Script {
+    calls: Vec<DeviceCall>,
+    failures: Vec<Failure>,
+    reads: VecDeque<ScriptedRead>,
+}
+
+struct Shared {
+    /// Whether the device only reports the scripted input and records calls, instead of
+    /// pressing buttons on its own
+    scripted: bool,
+    protocol_version: usize,
+    script: std::sync::Mutex<Script>,
+    read_ready: Notify,
+}
+
+/// Fake device that logs everything it's asked to do instead of talking to hardware
+///
+/// Created with [SimulatedDevice::new] it presses every button in turn for simulation mode.
+/// Created with [SimulatedDevice::scripted] it's a mock [DeviceIo] for exercising the device
+/// code without hardware, which records every call, fails the calls it's told to and only
+/// reports the input it's fed. Clones share the same device, so one can be kept to
+/// script and inspect a device that was handed over to the plugin
+#[derive(Clone)]
+pub struct SimulatedDevice {
+    shared: Arc<Shared>,
+}
+
+impl Default for SimulatedDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedDevice {
+    /// Creates a device that presses every button in turn, one per [SIMULATED_PRESS_INTERVAL]
+    pub fn new() -> Self {
+        Self::with_mode(false, Kind::AMPGD6.protocol_version())
+    }
+
+    /// Creates a mock device speaking the given protocol version, whose input is scripted
+    /// with [SimulatedDevice::feed_input] and whose calls are recorded
+    pub fn scripted(protocol_version: usize) -> Self {
+        Self::with_mode(true, protocol_version)
+    }
+
+    fn with_mode(scripted: bool, protocol_version: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                scripted,
+                protocol_version,
+                script: std::sync::Mutex::new(Script::default()),
+                read_ready: Notify::new(),
+            }),
+        }
+    }
+
+    fn script(&self) -> std::sync::MutexGuard<'_, Script> {
+        self.shared
+            .script
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns calls made so far, including failed ones, in the order they were made
+    pub fn calls(&self) -> Vec<DeviceCall> {
+        self.script().calls.clone()
+    }
+
+    /// Returns calls made so far and forgets them
+    pub fn take_calls(&self) -> Vec<DeviceCall> {
+        std::mem::take(&mut self.script().calls)
+    }
+
+    /// Makes the next given number of calls the matcher accepts fail as if the device was unplugged
+    pub fn fail_next(&self, matches: fn(&DeviceCall) -> bool, times: usize) {
+        self.script().failures.push(Failure {
+            matches,
+            remaining: times,
+        });
+    }
+
+    /// Queues raw input for the reader, run through the same processing as a real device's
+    pub fn feed_input(&self, input: u8, state: u8) {
+        self.feed(ScriptedRead::Input(input, state));
+    }
+
+    /// Queues updates the reader returns from one read as they are
+    pub fn feed_updates(&self, updates: Vec<DeviceStateUpdate>) {
+        self.feed(ScriptedRead::Updates(updates));
+    }
+
+    /// Queues an error the reader returns from one read
+    pub fn feed_error(&self, err: MirajazzError) {
+        self.feed(ScriptedRead::Error(err));
+    }
+
+    fn feed(&self, read: ScriptedRead) {
+        self.script().reads.push_back(read);
+        self.shared.read_ready.notify_one();
+    }
+
+    /// Records the call and returns the error injected for it, if any
+    fn call(&self, call: DeviceCall) -> Result<(), MirajazzError> {
+        let mut script = self.script();
+
+        let failed = match script
+            .failures
+            .iter_mut()
+            .find(|failure| (failure.matches)(&call))
+        {
+            Some(failure) => {
+                failure.remaining -= 1;
+
+                true
+            }
+            None => false,
+        };
+        script.failures.retain(|failure| failure.remaining > 0);
+
+        if self.shared.scripted {
+            script.calls.push(call);
+        }
+
+        if failed {
+            Err(MirajazzError::DeviceNotFoundError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl DeviceIo for SimulatedDevice {
+    type Reader = SimulatedReader;
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), MirajazzError> {
+        log::info!("[simulated] Setting brightness to {}", percent);
+
+        self.call(DeviceCall::SetBrightness(percent))
+    }
+
+    async fn clear_button_image(&self, key: u8) -> Result<(), MirajazzError> {
+        log::info!("[simulated] Clearing image of button {}", key);
+
+        self.call(DeviceCall::ClearButtonImage(key))
+    }
+
+    async fn clear_all_button_images(&self) -> Result<(), MirajazzError> {
+        log::info!("[simulated] Clearing all button images");
+
+        self.call(DeviceCall::ClearAllButtonImages)
+    }
+
+    async fn write_image(&self, key: u8, image_data: &[u8]) -> Result<(), MirajazzError> {
+        log::info!(
+            "[simulated] Received image for button {}, {} bytes",
+            key,
+            image_data.len()
+        );
+
+        self.call(DeviceCall::WriteImage(key, image_data.to_vec()))
+    }
+
+    async fn flush(&self) -> Result<(), MirajazzError> {
+        log::info!("[simulated] Flushing");
+
+        self.call(DeviceCall::Flush)
+    }
+
+    async fn keep_alive(&self) -> Result<(), MirajazzError> {
+        log::debug!("[simulated] Keep alive");
+
+        self.call(DeviceCall::KeepAlive)
+    }
+
+    async fn shutdown(&self) -> Result<(), MirajazzError> {
+        log::info!("[simulated] Shutting down");
+
+        self.call(DeviceCall::Shutdown)
+    }
+
+    fn get_reader(&self, process_input: InputProcessor) -> Self::Reader {
+        let presses = (!self.shared.scripted).then(|| {
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + SIMULATED_PRESS_INTERVAL,
+                SIMULATED_PRESS_INTERVAL,
+            );
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            Mutex::new(interval)
+        });
+
+        SimulatedReader {
+            device: self.clone(),
+            process_input,
+            presses,
+            next_input: AtomicU8::new(1),
+            states: std::sync::Mutex::new((vec![], vec![])),
+        }
+    }
+}
+
+/// Reader of a [SimulatedDevice], which either presses every button in turn, one per
+/// [SIMULATED_PRESS_INTERVAL], or reports the input fed to a scripted device
+pub struct SimulatedReader {
+    device: SimulatedDevice,
+    process_input: InputProcessor,
+    presses: Option<Mutex<Interval>>,
+    /// Next 1-based button index, as the firmware would report it
+    next_input: AtomicU8,
+    /// Last reported button and encoder states, to turn inputs into updates like mirajazz does
+    states: std::sync::Mutex<(Vec<bool>, Vec<bool>)>,
+}
+
+impl SimulatedReader {
+    /// Waits for the next scripted read, returning none if timeout was reached first
+    async fn next_read(&self, timeout: Option<Duration>) -> Option<ScriptedRead> {
+        let shared = &self.device.shared;
+
+        loop {
+            if let Some(read) = self.device.script().reads.pop_front() {
+                return Some(read);
+            }
+
+            match timeout {
+                Some(timeout) => {
+                    if tokio::time::timeout(timeout, shared.read_ready.notified())
+                        .await
+                        .is_err()
+                    {
+                        return None;
+                    }
+                }
+                None => shared.read_ready.notified().await,
+            }
+        }
+    }
+
+    /// Waits for the next press of simulation mode, returning none if timeout was reached first
+    async fn next_press(
+        &self,
+        interval: &Mutex<Interval>,
+        timeout: Option<Duration>,
+    ) -> Option<u8> {
+        let mut interval = interval.lock().await;
+
+        match timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, interval.tick())
+                    .await
+                    .is_err()
+                {
+                    return None;
+                }
+            }
+            None => {
+                interval.tick().await;
+            }
+        }
+
+        let input = self.next_input.load(Ordering::Relaxed);
+        self.next_input
+            .store(input % KEY_COUNT as u8 + 1, Ordering::Relaxed);
+
+        log::info!("[simulated] Pressing button {}", input);
+
+        Some(input)
+    }
+
+    /// Runs input through the same processing as the real device, reporting both states
+    /// at once if the protocol version can't tell them apart
+    fn input_to_updates(
+        &self,
+        input: u8,
+        state: u8,
+    ) -> Result<Vec<DeviceStateUpdate>, MirajazzError> {
+        let both_states = self.supports_both_states();
+        let state = if both_states { state } else { 0x1 };
+
+        let mut states = self
+            .states
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (buttons, encoders) = &mut *states;
+        let mut updates = vec![];
+
+        match (self.process_input)(input, state)? {
+            DeviceInput::ButtonStateChange(theirs) => {
+                push_changes(
+                    &mut updates,
+                    &theirs,
+                    buttons,
+                    both_states,
+                    DeviceStateUpdate::ButtonDown,
+                    DeviceStateUpdate::ButtonUp,
+                );
+                *buttons = theirs;
+            }
+            DeviceInput::EncoderStateChange(theirs) => {
+                push_changes(
+                    &mut updates,
+                    &theirs,
+                    encoders,
+                    both_states,
+                    DeviceStateUpdate::EncoderDown,
+                    DeviceStateUpdate::EncoderUp,
+                );
+                *encoders = theirs;
+            }
+            DeviceInput::EncoderTwist(twist) => {
+                for (index, change) in twist.iter().enumerate() {
+                    if *change != 0 {
+                        updates.push(DeviceStateUpdate::EncoderTwist(index as u8, *change));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(updates)
+    }
+}
+
+/// Pushes updates for states that changed, or a press and release for every pressed one
+/// if the protocol can't tell them apart, the same way mirajazz does
+fn push_changes(
+    updates: &mut Vec<DeviceStateUpdate>,
+    theirs: &[bool],
+    mine: &[bool],
+    both_states: bool,
+    down: fn(u8) -> DeviceStateUpdate,
+    up: fn(u8) -> DeviceStateUpdate,
+) {
+    for (index, their) in theirs.iter().enumerate() {
+        let mine = mine.get(index).copied().unwrap_or(false);
+
+        if !both_states {
+            if *their {
+                updates.push(down(index as u8));
+                updates.push(up(index as u8));
+            }
+        } else if *their != mine {
+            updates.push(if *their {
+                down(index as u8)
+            } else {
+                up(index as u8)
+            });
+        }
+    }
+}
+
+impl DeviceReader for SimulatedReader {
+    async fn read(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<DeviceStateUpdate>, MirajazzError> {
+        let read = match &self.presses {
+            Some(interval) => self
+                .next_press(interval, timeout)
+                .await
+                .map(|input| ScriptedRead::Input(input, 1)),
+            None => self.next_read(timeout).await,
+        };
+
+        match read {
+            Some(ScriptedRead::Input(input, state)) => self.input_to_updates(input, state),
+            Some(ScriptedRead::Updates(updates)) => Ok(updates),
+            Some(ScriptedRead::Error(err)) => Err(err),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn supports_both_states(&self) -> bool {
+        self.device.shared.protocol_version > 2
+    }
+}
+
+/// Runs a simulated device through the same init, registration and event handling as a real one
+pub async fn simulated_task(token: CancellationToken, config: &'static Config) {
+    let kind = Kind::AMPGD6;
+    let id = format!("{}-simulated-{}", DEVICE_NAMESPACE, kind.id_suffix());
+    let name = format!("{} (simulated)", kind.human_name());
+
+    log::info!("Running simulated device {}", id);
+
+    let device = DeviceHandle::new(
+        id.clone(),
+        DeviceBackend::Simulated(SimulatedDevice::new()),
+        kind.clone(),
+    );
+    match kind
+        .init_sequence(&device, initial_brightness(&id, config).await)
+        .await
+    {
+        Ok(failed) => log_init_outcome(&id, failed),
+        Err(err) => {
+            log::error!("Failed to initialize simulated device {}: {}", id, err);
+
+            return;
+        }
+    }
+
+    if !register_device(&id, name, device).await {
+        return;
+    }
+
+    tokio::select! {
+        _ = device_events_task(&id, config) => {},
+        _ = token.cancelled() => {}
+    };
+
+    log::info!("Shutting down simulated device {}", id);
+
+    remove_device(&id).await;
+
+    log::info!("Simulated device {} finished", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs::process_input;
+
+    #[tokio::test]
+    async fn scripted_device_records_calls() {
+        let device = SimulatedDevice::scripted(3);
+
+        device.set_brightness(40).await.unwrap();
+        device.write_image(2, &[1, 2, 3]).await.unwrap();
+        device.flush().await.unwrap();
+
+        assert_eq!(
+            device.take_calls(),
+            vec![
+                DeviceCall::SetBrightness(40),
+                DeviceCall::WriteImage(2, vec![1, 2, 3]),
+                DeviceCall::Flush
+            ]
+        );
+        assert!(device.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn injected_failures_run_out() {
+        let device = SimulatedDevice::scripted(3);
+        device.fail_next(|call| *call == DeviceCall::Flush, 2);
+
+        device.keep_alive().await.unwrap();
+        assert!(device.flush().await.is_err());
+        assert!(device.flush().await.is_err());
+        device.flush().await.unwrap();
+
+        // Failed calls are recorded too
+        assert_eq!(device.calls().len(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulation_presses_every_button_in_turn() {
+        let reader = SimulatedDevice::new().get_reader(process_input);
+
+        // Input 1 is OpenDeck 10 and input 2 OpenDeck 11 with the default key map
+        for key in [10, 11] {
+            let updates = reader.read(None).await.unwrap();
+            assert!(
+                matches!(
+                    updates.as_slice(),
+                    [DeviceStateUpdate::ButtonDown(down), DeviceStateUpdate::ButtonUp(up)]
+                        if *down == key && *up == key
+                ),
+                "{:?}",
+                updates
+            );
+        }
+
+        // Nothing is pressed between the ticks
+        let updates = reader
+            .read(Some(SIMULATED_PRESS_INTERVAL / 2))
+            .await
+            .unwrap();
+        assert!(updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_both_states_at_once_before_v3() {
+        let device = SimulatedDevice::scripted(1);
+        let reader = device.get_reader(process_input);
+
+        // The state byte is ignored, like the real reader does. Input 11 is OpenDeck 0 with
+        // the default key map
+        device.feed_input(11, 0);
+
+        let updates = reader.read(None).await.unwrap();
+        assert!(matches!(
+            updates.as_slice(),
+            [
+                DeviceStateUpdate::ButtonDown(0),
+                DeviceStateUpdate::ButtonUp(0)
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_press_and_release_separately_from_v3() {
+        let device = SimulatedDevice::scripted(3);
+        let reader = device.get_reader(process_input);
+
+        device.feed_input(11, 1);
+        device.feed_input(11, 0);
+
+        assert!(matches!(
+            reader.read(None).await.unwrap().as_slice(),
+            [DeviceStateUpdate::ButtonDown(0)]
+        ));
+        assert!(matches!(
+            reader.read(None).await.unwrap().as_slice(),
+            [DeviceStateUpdate::ButtonUp(0)]
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_times_out_without_input() {
+        let reader = SimulatedDevice::scripted(3).get_reader(process_input);
+
+        let updates = reader.read(Some(Duration::from_secs(1))).await.unwrap();
+        assert!(updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fed_errors_are_returned() {
+        let device = SimulatedDevice::scripted(3);
+        let reader = device.get_reader(process_input);
+
+        device.feed_error(MirajazzError::BadData);
+        device.feed_updates(vec![DeviceStateUpdate::EncoderTwist(0, 2)]);
+
+        assert!(matches!(
+            reader.read(None).await,
+            Err(MirajazzError::BadData)
+        ));
+        assert!(matches!(
+            reader.read(None).await.unwrap().as_slice(),
+            [DeviceStateUpdate::EncoderTwist(0, 2)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_input_is_an_error() {
+        let device = SimulatedDevice::scripted(3);
+        let reader = device.get_reader(process_input);
+
+        device.feed_input(0xff, 1);
+
+        assert!(matches!(
+            reader.read(None).await,
+            Err(MirajazzError::BadData)
+        ));
+    }
+}
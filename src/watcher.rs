@@ -5,11 +5,11 @@ use mirajazz::{
     types::{DeviceLifecycleEvent, HidDeviceInfo},
 };
 use openaction::OUTBOUND_EVENT_MANAGER;
-use tokio_util::sync::CancellationToken;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    DEVICES, TOKENS, TRACKER,
-    device::device_task,
+    CONFIG, DEVICES, TOKENS, TRACKER,
+    device::{DeviceEvent, emit_device_event, supervised_device_task},
     mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES},
 };
 
@@ -42,21 +42,54 @@ fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
     Some(CandidateDevice { id, dev, kind })
 }
 
+/// Command line flag printing the detected devices and exiting, see [list_candidates]
+pub const LIST_FLAG: &str = "--list";
+
+/// Returns true if the plugin was started with [LIST_FLAG]
+pub fn list_requested() -> bool {
+    std::env::args().any(|arg| arg == LIST_FLAG)
+}
+
 /// Returns devices that matches known pid/vid pairs
-async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
+pub async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
     log::info!("Looking for candidate devices");
 
-    let mut candidates: Vec<CandidateDevice> = Vec::new();
+    let devices = list_devices(&QUERIES).await?;
 
-    for dev in list_devices(&QUERIES).await? {
-        if let Some(candidate) = device_info_to_candidate(dev.clone()) {
-            candidates.push(candidate);
-        } else {
-            continue;
-        }
+    Ok(candidates_of(
+        devices.iter().map(|dev| HidDeviceInfo::clone(dev)),
+    ))
+}
+
+/// Returns devices of the enumerated ones that match known pid/vid pairs
+fn candidates_of(devices: impl IntoIterator<Item = HidDeviceInfo>) -> Vec<CandidateDevice> {
+    devices
+        .into_iter()
+        .filter_map(device_info_to_candidate)
+        .collect()
+}
+
+/// Prints every device [get_candidates] finds to stdout, for checking a device is matched by
+/// [QUERIES] without starting the plugin
+pub async fn list_candidates() -> Result<(), MirajazzError> {
+    let candidates = get_candidates().await?;
+
+    if candidates.is_empty() {
+        println!("No devices found");
     }
 
-    Ok(candidates)
+    for candidate in candidates {
+        println!(
+            "{:04x}:{:04x} serial={} kind={} id={}",
+            candidate.dev.vendor_id,
+            candidate.dev.product_id,
+            candidate.dev.serial_number.as_deref().unwrap_or("<none>"),
+            candidate.kind.human_name(),
+            candidate.id
+        );
+    }
+
+    Ok(())
 }
 
 pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError> {
@@ -68,16 +101,7 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
     log::info!("Looking for connected devices");
 
     for candidate in candidates {
-        log::info!("New candidate {:#?}", candidate);
-
-        let token = CancellationToken::new();
-
-        TOKENS
-            .write()
-            .await
-            .insert(candidate.id.clone(), token.clone());
-
-        tracker.spawn(device_task(candidate, token));
+        candidate_found(candidate, &tracker).await;
     }
 
     let mut watcher = DeviceWatcher::new();
@@ -95,42 +119,8 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
             log::info!("New device event: {:?}", ev);
 
             match ev {
-                DeviceLifecycleEvent::Connected(info) => {
-                    if let Some(candidate) = device_info_to_candidate(info) {
-                        // Don't add existing device again
-                        if DEVICES.read().await.contains_key(&candidate.id) {
-                            continue;
-                        }
-
-                        let token = CancellationToken::new();
-
-                        TOKENS
-                            .write()
-                            .await
-                            .insert(candidate.id.clone(), token.clone());
-
-                        log::debug!("Spawning task for new device: {:?}", candidate);
-                        tracker.spawn(device_task(candidate, token));
-                        log::debug!("Spawned");
-                    }
-                }
-                DeviceLifecycleEvent::Disconnected(info) => {
-                    let id = get_device_id(&info)
-                        .expect("Unable to get device id, check mappings in Kind::from_vid_pid");
-
-                    if let Some(token) = TOKENS.write().await.remove(&id) {
-                        log::info!("Sending cancel request for {}", id);
-                        token.cancel();
-                    }
-
-                    DEVICES.write().await.remove(&id);
-
-                    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                        outbound.deregister_device(id.clone()).await.ok();
-                    }
-
-                    log::info!("Disconnected device {}", id);
-                }
+                DeviceLifecycleEvent::Connected(info) => device_connected(info, &tracker).await,
+                DeviceLifecycleEvent::Disconnected(info) => device_disconnected(&info).await,
             }
         } else {
             log::info!("Watcher is shutting down");
@@ -139,3 +129,223 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
         }
     }
 }
+
+/// Spawns a task for a device found when the plugin starts
+async fn candidate_found(candidate: CandidateDevice, tracker: &TaskTracker) {
+    log::info!("New candidate {:#?}", candidate);
+
+    // The same device may be enumerated more than once, e.g. through duplicate HID paths,
+    // only the first entry gets a task so they don't race to register it
+    let mut tokens = TOKENS.write().await;
+
+    if tokens.contains_key(&candidate.id) {
+        log::warn!("Skipping duplicate candidate {}", candidate.id);
+        return;
+    }
+
+    let token = CancellationToken::new();
+    tokens.insert(candidate.id.clone(), token.clone());
+    drop(tokens);
+
+    tracker.spawn(supervised_device_task(candidate, token, &CONFIG));
+}
+
+/// Spawns a task for a device that was plugged in, unless it's already connected
+async fn device_connected(info: HidDeviceInfo, tracker: &TaskTracker) {
+    let Some(candidate) = device_info_to_candidate(info) else {
+        return;
+    };
+
+    // Don't add existing device again
+    if DEVICES.read().await.contains_key(&candidate.id) {
+        return;
+    }
+
+    let token = CancellationToken::new();
+
+    // A task that lost the device may still be waiting to reconnect,
+    // stop it so only one task is left per device
+    if let Some(previous) = TOKENS
+        .write()
+        .await
+        .insert(candidate.id.clone(), token.clone())
+    {
+        log::info!("Replacing reconnecting task for {}", candidate.id);
+        previous.cancel();
+    }
+
+    log::debug!("Spawning task for new device: {:?}", candidate);
+    tracker.spawn(supervised_device_task(candidate, token, &CONFIG));
+    log::debug!("Spawned");
+}
+
+/// Stops the task of a device that was unplugged and deregisters the device
+async fn device_disconnected(info: &HidDeviceInfo) {
+    let Some(id) = get_device_id(info) else {
+        log::warn!(
+            "Unable to get device id of disconnected {:?}, check mappings in Kind::from_vid_pid",
+            info
+        );
+        return;
+    };
+
+    if let Some(token) = TOKENS.write().await.remove(&id) {
+        log::info!("Sending cancel request for {}", id);
+        token.cancel();
+    }
+
+    if DEVICES.write().await.remove(&id).is_some() {
+        emit_device_event(DeviceEvent::Disconnected(id.clone()));
+    }
+
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        outbound.deregister_device(id.clone()).await.ok();
+    }
+
+    log::info!("Disconnected device {}", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_hid::DeviceId;
+
+    use super::*;
+    use crate::{
+        device::{DeviceBackend, DeviceHandle},
+        simulated::SimulatedDevice,
+    };
+
+    fn device_info(path: &str, product_id: u16) -> HidDeviceInfo {
+        HidDeviceInfo {
+            #[cfg(target_os = "linux")]
+            id: DeviceId::DevPath(path.into()),
+            #[cfg(target_os = "windows")]
+            id: DeviceId::UncPath(path.into()),
+            #[cfg(target_os = "macos")]
+            id: DeviceId::RegistryEntryId(path.len() as u64),
+            name: String::new(),
+            product_id,
+            vendor_id: 0x3142,
+            usage_id: 1,
+            usage_page: 0xffa0,
+            serial_number: Some("355499441494".to_string()),
+        }
+    }
+
+    #[test]
+    fn v1_device_id_does_not_depend_on_the_port() {
+        let first = get_device_id(&device_info("/dev/hidraw3", 0x0007)).unwrap();
+        let second = get_device_id(&device_info("/dev/hidraw14", 0x0007)).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            format!(
+                "{}-355499441494-{}",
+                DEVICE_NAMESPACE,
+                Kind::AMPGD6.id_suffix()
+            )
+        );
+    }
+
+    #[test]
+    fn only_known_devices_are_candidates() {
+        let mut unknown = device_info("/dev/hidraw-test-unknown", 0x0009);
+        unknown.serial_number = None;
+        let devices = vec![
+            device_info("/dev/hidraw-test-v1", 0x0007),
+            unknown,
+            device_info("/dev/hidraw-test-v2", 0x0008),
+        ];
+
+        let candidates = candidates_of(devices);
+
+        let kinds: Vec<Kind> = candidates.iter().map(|c| c.kind.clone()).collect();
+        assert_eq!(kinds, [Kind::AMPGD6, Kind::AMPGD6V2]);
+        assert_eq!(
+            candidates[1].id,
+            format!("{}-355499441494", DEVICE_NAMESPACE)
+        );
+    }
+
+    #[tokio::test]
+    async fn device_plugged_in_mid_run_gets_a_task() {
+        let info = device_info("/dev/hidraw-test-hotplug", 0x0007);
+        let id = get_device_id(&info).unwrap();
+        let tracker = TaskTracker::new();
+
+        device_connected(info.clone(), &tracker).await;
+
+        assert_eq!(tracker.len(), 1);
+        assert!(TOKENS.read().await.contains_key(&id));
+
+        device_disconnected(&info).await;
+        tracker.close();
+        tracker.wait().await;
+    }
+
+    #[tokio::test]
+    async fn connected_device_gets_no_second_task() {
+        let info = device_info("/dev/hidraw-test-connected", 0x0007);
+        let id = get_device_id(&info).unwrap();
+        let device = DeviceHandle::new(
+            id.clone(),
+            DeviceBackend::Simulated(SimulatedDevice::scripted(1)),
+            Kind::AMPGD6,
+        );
+        DEVICES.write().await.insert(id.clone(), Arc::new(device));
+        let tracker = TaskTracker::new();
+
+        device_connected(info.clone(), &tracker).await;
+
+        assert!(tracker.is_empty());
+        assert!(!TOKENS.read().await.contains_key(&id));
+
+        device_disconnected(&info).await;
+        assert!(!DEVICES.read().await.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn duplicate_candidates_get_one_task() {
+        let info = device_info("/dev/hidraw-test-duplicate", 0x0007);
+        let id = get_device_id(&info).unwrap();
+        let tracker = TaskTracker::new();
+
+        candidate_found(device_info_to_candidate(info.clone()).unwrap(), &tracker).await;
+        let token = TOKENS.read().await.get(&id).cloned().unwrap();
+        candidate_found(device_info_to_candidate(info.clone()).unwrap(), &tracker).await;
+
+        assert_eq!(tracker.len(), 1);
+        assert!(!token.is_cancelled());
+
+        device_disconnected(&info).await;
+        tracker.close();
+        tracker.wait().await;
+    }
+
+    #[tokio::test]
+    async fn replugging_leaves_no_tokens_behind() {
+        let info = device_info("/dev/hidraw-test-replug", 0x0007);
+        let id = get_device_id(&info).unwrap();
+        let tracker = TaskTracker::new();
+
+        for _ in 0..2 {
+            device_connected(info.clone(), &tracker).await;
+            assert!(TOKENS.read().await.contains_key(&id));
+
+            device_disconnected(&info).await;
+            assert!(!TOKENS.read().await.contains_key(&id));
+        }
+
+        // The tasks stop without putting anything back
+        tracker.close();
+        tokio::time::timeout(std::time::Duration::from_secs(5), tracker.wait())
+            .await
+            .expect("Device tasks didn't stop");
+
+        assert!(!TOKENS.read().await.contains_key(&id));
+        assert!(!DEVICES.read().await.contains_key(&id));
+    }
+}
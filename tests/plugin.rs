@@ -0,0 +1,35 @@
+//! Running the plugin sets the config and the discovery token globally, so it runs in a
+//! process of its own rather than next to the unit tests
+
+use std::time::Duration;
+
+use opendeck_ampgd6::{CONFIG, DEVICES, config::Config, plugin::Plugin};
+
+#[tokio::test]
+async fn simulated_plugin_registers_its_device_until_stopped() {
+    let config = Config {
+        brightness: 40,
+        ..Config::default()
+    };
+
+    let plugin = Plugin::new()
+        .with_config(config)
+        .simulated(true)
+        .run()
+        .await;
+    assert_eq!(CONFIG.brightness, 40);
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while DEVICES.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Simulated device wasn't registered");
+
+    tokio::time::timeout(Duration::from_secs(5), plugin.stop())
+        .await
+        .expect("Stopping the plugin waited for the timeout");
+
+    assert!(DEVICES.read().await.is_empty());
+}
@@ -0,0 +1,58 @@
+//! Shutdown waits for every connected device, so it runs in a process of its own rather than
+//! next to the unit tests, which keep their devices connected
+
+use std::time::Duration;
+
+use opendeck_ampgd6::{
+    DEVICES, TOKENS,
+    device::{DeviceBackend, DeviceHandle, register_device, remove_device, shutdown_all},
+    mappings::Kind,
+    simulated::{DeviceCall, SimulatedDevice},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Registers a scripted device and removes it once its token is cancelled, like a device task
+async fn simulated_device(id: &str) -> SimulatedDevice {
+    let device = SimulatedDevice::scripted(1);
+    let handle = DeviceHandle::new(
+        id.to_string(),
+        DeviceBackend::Simulated(device.clone()),
+        Kind::AMPGD6,
+    );
+    assert!(register_device(id, Kind::AMPGD6.human_name(), handle).await);
+
+    let token = CancellationToken::new();
+    TOKENS.write().await.insert(id.to_string(), token.clone());
+
+    let id = id.to_string();
+    tokio::spawn(async move {
+        token.cancelled().await;
+        remove_device(&id).await;
+    });
+
+    device
+}
+
+#[tokio::test]
+async fn shutdown_removes_every_device() {
+    let devices = [
+        simulated_device("test-shutdown-first").await,
+        simulated_device("test-shutdown-second").await,
+    ];
+
+    tokio::time::timeout(Duration::from_secs(2), shutdown_all())
+        .await
+        .expect("Shutdown waited for the timeout");
+
+    assert!(DEVICES.read().await.is_empty());
+    for device in devices {
+        assert_eq!(
+            device.calls(),
+            [
+                DeviceCall::ClearAllButtonImages,
+                DeviceCall::Flush,
+                DeviceCall::Shutdown
+            ]
+        );
+    }
+}